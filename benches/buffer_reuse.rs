@@ -0,0 +1,73 @@
+//! Per-file buffer allocation versus per-thread reuse when JPEG-encoding a
+//! folder's worth of small images, timing the library's `compress_jpeg`
+//! against a copy of the allocate-per-file encoder it replaced.
+//!
+//! Run with `cargo bench --bench buffer_reuse`.
+
+use image::{codecs::jpeg::JpegEncoder, DynamicImage, ExtendedColorType, ImageEncoder, RgbaImage};
+use rayon::prelude::*;
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+const IMAGES: usize = 2_000;
+const SIZE: u32 = 96;
+const QUALITY: u8 = 55;
+const ROUNDS: usize = 5;
+
+fn main() {
+    // RGBA sources, like PNG icons and screenshots, take the conversion path
+    let images: Vec<DynamicImage> = (0..IMAGES)
+        .map(|i| {
+            DynamicImage::ImageRgba8(RgbaImage::from_fn(SIZE, SIZE, |x, y| {
+                let shade = (x * 3 + y * 5 + i as u32) as u8;
+                image::Rgba([shade, shade.wrapping_mul(7), 255 - shade, 255])
+            }))
+        })
+        .collect();
+
+    // Across the rayon pool, as a batch runs
+    let fresh = best_of(|| {
+        images.par_iter().for_each(|img| {
+            black_box(encode_fresh(img));
+        });
+    });
+    let reused = best_of(|| {
+        images.par_iter().for_each(|img| {
+            black_box(pixelsqueeze::compress_jpeg(img, f32::from(QUALITY)).expect("encode"));
+        });
+    });
+
+    let per_image = |total: Duration| total / IMAGES as u32;
+    println!(
+        "{} {}x{} RGBA images to JPEG on {} threads, best of {} rounds",
+        IMAGES,
+        SIZE,
+        SIZE,
+        rayon::current_num_threads(),
+        ROUNDS
+    );
+    println!("  fresh buffers:  {:>10.2?} total, {:>8.2?} per image", fresh, per_image(fresh));
+    println!("  reused buffers: {:>10.2?} total, {:>8.2?} per image", reused, per_image(reused));
+    println!("  speedup:        {:>10.2}x", fresh.as_secs_f64() / reused.as_secs_f64());
+}
+
+fn best_of(mut run: impl FnMut()) -> Duration {
+    (0..ROUNDS)
+        .map(|_| {
+            let started = Instant::now();
+            run();
+            started.elapsed()
+        })
+        .min()
+        .unwrap_or_default()
+}
+
+/// What `compress_jpeg` did before: a new RGB image and a growing `Vec` per file.
+fn encode_fresh(img: &DynamicImage) -> Vec<u8> {
+    let rgb = img.to_rgb8();
+    let mut buffer = Vec::new();
+    JpegEncoder::new_with_quality(&mut buffer, QUALITY)
+        .write_image(rgb.as_raw(), rgb.width(), rgb.height(), ExtendedColorType::Rgb8)
+        .expect("encode");
+    buffer
+}
@@ -0,0 +1,146 @@
+//! The `analyze` subcommand.
+
+use anyhow::{Context, Result};
+use colored::Colorize;
+use comfy_table::Table;
+use humansize::{format_size, DECIMAL};
+use rayon::prelude::*;
+use serde::Serialize;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::cli::AnalyzeArgs;
+use crate::decode::jpeg_cmyk_layout;
+use crate::error::describe_error;
+use crate::pipeline::print_no_files_found;
+use crate::plan::{collect_image_files, WalkOptions};
+use crate::report::{create_scan_spinner, print_banner};
+
+#[derive(Serialize)]
+pub(crate) struct ImageAnalysis {
+    pub(crate) path: String,
+    pub(crate) format: String,
+    pub(crate) width: u32,
+    pub(crate) height: u32,
+    pub(crate) color_type: String,
+    pub(crate) bit_depth: u16,
+    pub(crate) file_size: u64,
+    pub(crate) has_alpha: bool,
+    pub(crate) has_exif: bool,
+}
+
+#[derive(Serialize)]
+pub(crate) struct AnalysisError {
+    pub(crate) path: String,
+    pub(crate) message: String,
+}
+
+#[derive(Serialize)]
+pub(crate) struct AnalysisReport {
+    pub(crate) images: Vec<ImageAnalysis>,
+    pub(crate) errors: Vec<AnalysisError>,
+}
+
+pub(crate) fn run_analyze(args: &AnalyzeArgs) -> Result<()> {
+    let walk = WalkOptions { recursive: args.recursive, include_hidden: args.include_hidden, exclude_dirs: &[], since: None };
+    let files = collect_image_files(std::slice::from_ref(&args.input), &walk, &create_scan_spinner())?;
+
+    let results: Vec<(PathBuf, Result<ImageAnalysis>)> = files.par_iter()
+        .map(|path| (path.clone(), analyze_image(path)))
+        .collect();
+
+    let mut report = AnalysisReport { images: Vec::new(), errors: Vec::new() };
+    for (path, result) in results {
+        match result {
+            Ok(analysis) => report.images.push(analysis),
+            Err(e) => report.errors.push(AnalysisError {
+                path: path.display().to_string(),
+                message: describe_error(&e),
+            }),
+        }
+    }
+
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+        return Ok(());
+    }
+
+    print_banner();
+    if report.images.is_empty() && report.errors.is_empty() {
+        print_no_files_found();
+        return Ok(());
+    }
+
+    print_analysis(&report);
+    Ok(())
+}
+
+pub(crate) fn analyze_image(path: &Path) -> Result<ImageAnalysis> {
+    use image::ImageDecoder;
+
+    let file_size = fs::metadata(path)?.len();
+    let reader = image::ImageReader::open(path)
+        .with_context(|| format!("Failed to open image: {}", path.display()))?
+        .with_guessed_format()?;
+    let format = reader.format()
+        .map_or_else(|| "unknown".to_string(), |f| format!("{:?}", f).to_uppercase());
+
+    // Only the header is read; pixel data is never decoded
+    let mut decoder = reader.into_decoder()
+        .with_context(|| format!("Failed to read image header: {}", path.display()))?;
+    let (width, height) = decoder.dimensions();
+    let color_type = decoder.color_type();
+    let has_exif = decoder.exif_metadata().ok().flatten().is_some_and(|exif| !exif.is_empty());
+    // The JPEG decoder reports CMYK sources as the RGB it converts them to
+    let is_cmyk = format == "JPEG"
+        && fs::File::open(path).ok().and_then(|file| jpeg_cmyk_layout(std::io::BufReader::new(file))).is_some();
+
+    Ok(ImageAnalysis {
+        path: path.display().to_string(),
+        format,
+        width,
+        height,
+        color_type: if is_cmyk { "Cmyk8".to_string() } else { format!("{:?}", color_type) },
+        bit_depth: color_type.bits_per_pixel() / u16::from(color_type.channel_count()),
+        file_size,
+        has_alpha: color_type.has_alpha(),
+        has_exif,
+    })
+}
+
+pub(crate) fn print_analysis(report: &AnalysisReport) {
+    if !report.images.is_empty() {
+        let mut table = Table::new();
+        table.set_header(vec!["Filename", "Format", "Dimensions", "Color", "Depth", "Size", "Alpha", "EXIF"]);
+
+        for image in &report.images {
+            let yes_no = |flag: bool| if flag { "yes" } else { "no" };
+            table.add_row(vec![
+                image.path.clone(),
+                image.format.clone(),
+                format!("{}x{}", image.width, image.height),
+                image.color_type.clone(),
+                format!("{}-bit", image.bit_depth),
+                format_size(image.file_size, DECIMAL),
+                yes_no(image.has_alpha).to_string(),
+                yes_no(image.has_exif).to_string(),
+            ]);
+        }
+
+        println!("{}", table);
+    }
+
+    let total_size: u64 = report.images.iter().map(|image| image.file_size).sum();
+    println!();
+    println!("{} images analyzed", report.images.len().to_string().bright_white().bold());
+    println!("Total size: {}", format_size(total_size, DECIMAL).bright_cyan());
+
+    if !report.errors.is_empty() {
+        println!("{} errors", report.errors.len());
+        for error in &report.errors {
+            println!("{} {}", error.path.bright_red(), error.message);
+        }
+    }
+}
@@ -0,0 +1,168 @@
+//! The `bench` subcommand.
+
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use colored::Colorize;
+use comfy_table::Table;
+use humansize::{format_size, DECIMAL};
+use rayon::prelude::*;
+use serde::Serialize;
+use std::{path::PathBuf, time::Instant};
+
+use crate::cli::{is_valid_quality, validate_encoder_args, BenchArgs, OutputFormat};
+use crate::codec::encode_resolved;
+use crate::report::print_banner;
+
+#[derive(Serialize)]
+pub(crate) struct BenchResult {
+    pub(crate) format: String,
+    pub(crate) threads: usize,
+    pub(crate) files: usize,
+    pub(crate) seconds: f64,
+    pub(crate) megapixels_per_second: f64,
+    pub(crate) files_per_second: f64,
+    pub(crate) output_bytes: u64,
+}
+
+pub(crate) fn run_bench(args: &BenchArgs) -> Result<()> {
+    if !args.quality.is_none_or(is_valid_quality) {
+        anyhow::bail!("Quality must be above 0 and at most 100");
+    }
+    if args.iterations == 0 {
+        anyhow::bail!("--iterations must be at least 1");
+    }
+    if args.threads.contains(&0) {
+        anyhow::bail!("--threads must be at least 1");
+    }
+    for format in &args.formats {
+        validate_encoder_args(&args.encoder, format)?;
+    }
+
+    let samples = if args.inputs.is_empty() {
+        bench_samples()
+    } else {
+        args.inputs.iter()
+            .map(|path| {
+                let img = image::open(path)
+                    .with_context(|| format!("Failed to open image: {}", path.display()))?;
+                Ok((path.clone(), img))
+            })
+            .collect::<Result<Vec<_>>>()?
+    };
+    let mut threads = args.threads.clone();
+    if threads.is_empty() {
+        threads = vec![1, num_cpus::get().max(1)];
+    }
+    threads.dedup();
+
+    if !args.json {
+        print_banner();
+        let megapixels: u64 = samples.iter().map(|(_, img)| u64::from(img.width()) * u64::from(img.height())).sum();
+        println!(
+            "Encoding {} samples ({:.1} MP), {} passes per measurement",
+            samples.len().to_string().bright_green(),
+            megapixels as f64 / 1_000_000.0,
+            args.iterations,
+        );
+    }
+
+    let mut results = Vec::new();
+    for format in &args.formats {
+        // Untimed pass so first-touch allocations and scratch buffers are not measured
+        for (path, img) in &samples {
+            encode_resolved(img, format, args.quality, path, &args.encoder)?;
+        }
+        for &thread_count in &threads {
+            results.push(bench_format(&samples, format, thread_count, args)?);
+        }
+    }
+
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&results)?);
+        return Ok(());
+    }
+
+    let mut table = Table::new();
+    table.set_header(vec!["Format", "Threads", "Files", "Time", "MP/s", "Files/s", "Avg size"]);
+    for result in &results {
+        table.add_row(vec![
+            result.format.clone(),
+            result.threads.to_string(),
+            result.files.to_string(),
+            format!("{:.2}s", result.seconds),
+            format!("{:.1}", result.megapixels_per_second),
+            format!("{:.1}", result.files_per_second),
+            format_size(result.output_bytes / result.files.max(1) as u64, DECIMAL),
+        ]);
+    }
+    println!("{}", table);
+    Ok(())
+}
+
+/// Encode every sample `iterations` times on a pool of `thread_count` workers,
+/// through the same in-memory encoders a compress run uses.
+pub(crate) fn bench_format(
+    samples: &[(PathBuf, image::DynamicImage)],
+    format: &OutputFormat,
+    thread_count: usize,
+    args: &BenchArgs,
+) -> Result<BenchResult> {
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(thread_count)
+        .build()
+        .context("Failed to start the benchmark thread pool")?;
+    let jobs: Vec<&(PathBuf, image::DynamicImage)> = (0..args.iterations)
+        .flat_map(|_| samples.iter())
+        .collect();
+
+    let start = Instant::now();
+    let encoded = pool.install(|| {
+        jobs.par_iter()
+            .map(|(path, img)| {
+                let (_, data) = encode_resolved(img, format, args.quality, path, &args.encoder)?;
+                Ok(data.len() as u64)
+            })
+            .collect::<Result<Vec<_>>>()
+    })?;
+    let seconds = start.elapsed().as_secs_f64().max(f64::EPSILON);
+
+    let pixels: u64 = jobs.iter().map(|(_, img)| u64::from(img.width()) * u64::from(img.height())).sum();
+    Ok(BenchResult {
+        format: format.to_possible_value().map_or_else(String::new, |value| value.get_name().to_string()),
+        threads: thread_count,
+        files: jobs.len(),
+        seconds,
+        megapixels_per_second: pixels as f64 / 1_000_000.0 / seconds,
+        files_per_second: jobs.len() as f64 / seconds,
+        output_bytes: encoded.iter().sum(),
+    })
+}
+
+/// Deterministic stand-ins for a typical batch: a noisy photo-like frame, a flat
+/// graphic with few colors and a translucent texture.
+pub(crate) fn bench_samples() -> Vec<(PathBuf, image::DynamicImage)> {
+    let noise = |x: u32, y: u32| (x.wrapping_mul(2_654_435_761) ^ y.wrapping_mul(40_503)) >> 7;
+
+    let photo = image::RgbImage::from_fn(1920, 1080, |x, y| {
+        let n = noise(x, y);
+        image::Rgb([
+            ((x / 8 + n % 23) % 256) as u8,
+            ((y / 5 + n % 31) % 256) as u8,
+            ((x + y) / 12 % 256) as u8,
+        ])
+    });
+    let graphic = image::RgbImage::from_fn(1280, 720, |x, y| {
+        const COLORS: [[u8; 3]; 4] = [[244, 244, 240], [32, 96, 200], [230, 80, 40], [40, 40, 48]];
+        image::Rgb(COLORS[((x / 160 + y / 120) % 4) as usize])
+    });
+    let texture = image::RgbaImage::from_fn(800, 800, |x, y| {
+        let n = noise(x, y);
+        image::Rgba([(n % 256) as u8, (x % 256) as u8, (y % 256) as u8, ((x + y) * 255 / 1600) as u8])
+    });
+
+    vec![
+        (PathBuf::from("photo.png"), image::DynamicImage::ImageRgb8(photo)),
+        (PathBuf::from("graphic.png"), image::DynamicImage::ImageRgb8(graphic)),
+        (PathBuf::from("texture.png"), image::DynamicImage::ImageRgba8(texture)),
+    ]
+}
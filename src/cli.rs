@@ -0,0 +1,1418 @@
+//! Command-line arguments, their parsers and cross-flag validation.
+
+use anyhow::{Context, Result};
+use clap::{parser::ValueSource, ArgMatches, CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use crate::plan::{is_image_file, relative_to_root, Job};
+use crate::report::print_warning;
+use crate::similarity::image_complexity;
+
+#[derive(Parser)]
+#[command(
+    name = "pixelsqueeze",
+    about = "PixelSqueeze - High-performance image compression",
+    long_about = "Lightning-fast image compression that reduces file sizes while maintaining quality.\nSupports JPEG, PNG, and WebP formats with progress tracking and batch processing.",
+    version
+)]
+pub(crate) struct Cli {
+    #[command(subcommand)]
+    pub(crate) command: Command,
+}
+
+#[derive(Subcommand)]
+pub(crate) enum Command {
+    /// Compress images (the default when no subcommand is given)
+    Compress(Box<Args>),
+    /// Report format, dimensions and metadata of images without writing anything
+    Analyze(AnalyzeArgs),
+    /// Encode one image at several qualities and compare size and SSIM
+    Compare(CompareArgs),
+    /// Measure encode throughput per format and thread count
+    Bench(BenchArgs),
+}
+
+pub(crate) const SUBCOMMANDS: &[&str] = &["compress", "analyze", "compare", "bench", "help"];
+
+#[derive(clap::Args)]
+pub(crate) struct AnalyzeArgs {
+    #[arg(help = "Input file or directory path")]
+    pub(crate) input: PathBuf,
+
+    #[arg(short, long, help = "Recursive directory processing")]
+    pub(crate) recursive: bool,
+
+    #[arg(long, help = "Also process dot-prefixed files and directories")]
+    pub(crate) include_hidden: bool,
+
+    #[arg(long, help = "Print the report as JSON")]
+    pub(crate) json: bool,
+}
+
+#[derive(clap::Args)]
+pub(crate) struct CompareArgs {
+    #[arg(help = "Input image file")]
+    pub(crate) input: PathBuf,
+
+    #[arg(
+        short,
+        long,
+        value_delimiter = ',',
+        default_values_t = [60.0, 75.0, 90.0],
+        help = "Comma-separated qualities to try, each above 0 and up to 100"
+    )]
+    pub(crate) quality: Vec<f32>,
+
+    #[arg(short, long, default_value = "webp", help = "Output format")]
+    pub(crate) format: OutputFormat,
+
+    #[arg(short, long, help = "Also write each candidate into this directory")]
+    pub(crate) output: Option<PathBuf>,
+
+    #[command(flatten)]
+    pub(crate) encoder: EncoderArgs,
+}
+
+#[derive(clap::Args)]
+pub(crate) struct BenchArgs {
+    #[arg(help = "Sample images to encode; built-in generated samples are used when none are given")]
+    pub(crate) inputs: Vec<PathBuf>,
+
+    #[arg(
+        short,
+        long,
+        value_delimiter = ',',
+        default_values = ["jpeg", "png", "webp"],
+        help = "Comma-separated formats to measure"
+    )]
+    pub(crate) formats: Vec<OutputFormat>,
+
+    #[arg(short, long, help = "Compression quality, up to 100 [default: 75 for JPEG, 80 for WebP]")]
+    pub(crate) quality: Option<f32>,
+
+    #[arg(
+        short,
+        long,
+        value_delimiter = ',',
+        help = "Comma-separated thread counts to measure [default: 1 and every core]"
+    )]
+    pub(crate) threads: Vec<usize>,
+
+    #[arg(long, default_value = "4", help = "Encode passes over the samples per measurement")]
+    pub(crate) iterations: u32,
+
+    #[arg(long, help = "Print the results as JSON")]
+    pub(crate) json: bool,
+
+    #[command(flatten)]
+    pub(crate) encoder: EncoderArgs,
+}
+
+/// Format-specific encoder knobs shared by every command that encodes.
+#[derive(Clone, clap::Args)]
+pub(crate) struct EncoderArgs {
+    #[arg(
+        long,
+        value_delimiter = ',',
+        default_values_t = [16, 32, 48],
+        help = "Icon sizes to pack into .ico output (max 256)"
+    )]
+    pub(crate) ico_sizes: Vec<u32>,
+
+    #[arg(
+        long,
+        help = "Encode losslessly: lossless WebP, PNG, and best picks between those two; overrides --quality"
+    )]
+    pub(crate) lossless: bool,
+
+    #[arg(long, help = "Rebuild JPEG Huffman tables for this image; lossless, typically a few percent smaller")]
+    pub(crate) jpeg_optimize: bool,
+
+    #[arg(long, value_name = "N", help = "Quantize PNG output to a palette of N colors; also sizes the GIF palette (2-256)")]
+    pub(crate) png_colors: Option<u16>,
+
+    #[arg(long, default_value = "best", help = "PNG deflate effort; fast trades a little size for much quicker encodes")]
+    pub(crate) png_compression: PngCompression,
+
+    #[arg(long, default_value = "adaptive", help = "PNG scanline filter")]
+    pub(crate) png_filter: PngFilter,
+
+    #[arg(long, help = "Write Adam7-interlaced PNGs for progressive display (usually a few percent larger)")]
+    pub(crate) png_interlace: bool,
+
+    #[arg(
+        long,
+        value_name = "1-8",
+        help = "Posterize PNG output to this many bits per color channel before encoding; lossy, dithered per --dither"
+    )]
+    pub(crate) png_bit_depth: Option<u8>,
+
+    #[arg(long, default_value = "floyd-steinberg", help = "Error diffusion used when quantizing to a palette")]
+    pub(crate) dither: Dither,
+
+    #[arg(long, value_name = "0-1", default_value = "0.75", help = "Strength of the dither error diffusion")]
+    pub(crate) dither_level: f32,
+
+    #[arg(long, value_name = "MODE", default_value = "auto", help = "How lossy WebP encodes images with transparency")]
+    pub(crate) webp_alpha: WebpAlpha,
+
+    #[arg(
+        long,
+        value_name = "0-100",
+        default_value = "100",
+        help = "Quality of the alpha plane when --webp-alpha encodes lossy; below 100 also smooths it"
+    )]
+    pub(crate) webp_alpha_quality: u8,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub(crate) enum WebpAlpha {
+    /// Lossless for flat graphics with few colors, lossy with alpha otherwise
+    Auto,
+    /// Keep transparent images pixel-exact
+    Lossless,
+    /// Lossy color plus a separately compressed alpha plane
+    Lossy,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub(crate) enum PngCompression {
+    Fast,
+    Default,
+    Best,
+}
+
+impl PngCompression {
+    pub(crate) const fn image_type(self) -> image::codecs::png::CompressionType {
+        use image::codecs::png::CompressionType;
+        match self {
+            Self::Fast => CompressionType::Fast,
+            Self::Default => CompressionType::Default,
+            Self::Best => CompressionType::Best,
+        }
+    }
+
+    pub(crate) const fn png_type(self) -> png::Compression {
+        match self {
+            Self::Fast => png::Compression::Fast,
+            Self::Default => png::Compression::Balanced,
+            Self::Best => png::Compression::High,
+        }
+    }
+
+    pub(crate) const fn zlib_level(self) -> u32 {
+        match self {
+            Self::Fast => 1,
+            Self::Default => 6,
+            Self::Best => 9,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub(crate) enum PngFilter {
+    None,
+    Sub,
+    Up,
+    Avg,
+    Paeth,
+    /// Pick the best filter per scanline
+    Adaptive,
+}
+
+impl PngFilter {
+    pub(crate) const fn image_type(self) -> image::codecs::png::FilterType {
+        use image::codecs::png::FilterType;
+        match self {
+            Self::None => FilterType::NoFilter,
+            Self::Sub => FilterType::Sub,
+            Self::Up => FilterType::Up,
+            Self::Avg => FilterType::Avg,
+            Self::Paeth => FilterType::Paeth,
+            Self::Adaptive => FilterType::Adaptive,
+        }
+    }
+
+    pub(crate) const fn png_type(self) -> png::Filter {
+        match self {
+            Self::None => png::Filter::NoFilter,
+            Self::Sub => png::Filter::Sub,
+            Self::Up => png::Filter::Up,
+            Self::Avg => png::Filter::Avg,
+            Self::Paeth => png::Filter::Paeth,
+            Self::Adaptive => png::Filter::Adaptive,
+        }
+    }
+
+    /// The filter type byte written before each scanline, or `None` when chosen per line.
+    pub(crate) const fn row_filter(self) -> Option<u8> {
+        match self {
+            Self::None => Some(0),
+            Self::Sub => Some(1),
+            Self::Up => Some(2),
+            Self::Avg => Some(3),
+            Self::Paeth => Some(4),
+            Self::Adaptive => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub(crate) enum Dither {
+    /// Map every pixel to its nearest palette color
+    None,
+    /// Spread each pixel's quantization error onto its unvisited neighbours
+    FloydSteinberg,
+}
+
+/// Options of the `compress` subcommand, which also drive the library API.
+#[derive(Clone, clap::Args)]
+pub struct Args {
+    #[arg(required = true, help = "Input files or directories")]
+    pub(crate) input: Vec<PathBuf>,
+
+    #[arg(short, long, help = "Output directory (default: ./compressed)")]
+    pub(crate) output: Option<PathBuf>,
+
+    #[arg(
+        long,
+        conflicts_with_all = ["output", "widths"],
+        help = "Write each output next to its source instead of into one output directory"
+    )]
+    pub(crate) output_same_dir: bool,
+
+    #[arg(
+        long,
+        value_name = "PATH",
+        conflicts_with = "output_same_dir",
+        help = "Mirror each source's directory below PATH into the output directory"
+    )]
+    pub(crate) input_root: Option<PathBuf>,
+
+    #[arg(
+        long,
+        value_name = "N",
+        conflicts_with = "output_same_dir",
+        help = "Mirror only the top N directory levels below the input root and flatten anything deeper into them"
+    )]
+    pub(crate) keep_structure_depth: Option<usize>,
+
+    #[arg(long, help = "Fail instead of creating the output directory (and --input-root subdirectories) when missing")]
+    pub(crate) no_dir_create: bool,
+
+    #[arg(
+        long,
+        value_name = "TEXT",
+        allow_hyphen_values = true,
+        help = "Append TEXT to every output name, e.g. -min for photo-min.webp"
+    )]
+    pub(crate) suffix: Option<String>,
+
+    #[arg(
+        short,
+        long,
+        help = "Compression quality, up to 100; fractions like 72.5 reach WebP, JPEG rounds to a whole number [default: 75 for JPEG, 80 for WebP]"
+    )]
+    pub(crate) quality: Option<f32>,
+
+    #[arg(
+        long,
+        value_name = "MIN-MAX",
+        num_args = 0..=1,
+        default_missing_value = "40-85",
+        value_parser = parse_quality_band,
+        conflicts_with = "quality",
+        help = "Pick each file's quality from its detail: flat images toward MIN, busy ones toward MAX [default band: 40-85]"
+    )]
+    pub(crate) auto_quality: Option<QualityBand>,
+
+    #[arg(
+        long,
+        value_name = "STEP",
+        num_args = 0..=1,
+        default_missing_value = "10",
+        help = "When a JPEG or WebP output isn't smaller than its source, re-encode STEP lower until it is or --quality-floor is hit [default step: 10]"
+    )]
+    pub(crate) quality_steps: Option<f32>,
+
+    #[arg(long, value_name = "Q", default_value = "20", help = "Lowest quality --quality-steps goes down to")]
+    pub(crate) quality_floor: f32,
+
+    #[arg(short, long, help = "Print per-file details, such as the quality --auto-quality or --quality-steps settled on")]
+    pub(crate) verbose: bool,
+
+    #[arg(long, help = "Name files by their path below the input in the results table, --manifest and --progress-json")]
+    pub(crate) full_paths: bool,
+
+    #[arg(
+        long,
+        default_value = "0",
+        help = "Minimum compression savings percentage to keep file (0-100)"
+    )]
+    pub(crate) min_savings: f64,
+
+    #[arg(
+        long,
+        help = "Keep metadata (EXIF, etc.) in compressed images; JPEG and WebP outputs also take the source EXIF and XMP"
+    )]
+    pub(crate) keep_metadata: bool,
+
+    #[arg(long, help = "With --keep-metadata, drop the EXIF thumbnail that camera JPEGs embed but keep every other tag")]
+    pub(crate) strip_thumbnail: bool,
+
+    #[arg(long, help = "Rotate/flip pixels to match the EXIF orientation, which only --keep-metadata JPEG and WebP outputs carry")]
+    pub(crate) auto_orient: bool,
+
+    #[arg(short, long, default_value = "png", help = "Output format")]
+    pub(crate) format: OutputFormat,
+
+    #[arg(
+        long,
+        value_name = "IN=OUT",
+        value_delimiter = ',',
+        value_parser = parse_format_mapping,
+        help = "Output format per input format (e.g. png=png,jpg=webp); unmapped inputs use --format"
+    )]
+    pub(crate) map: Vec<FormatMapping>,
+
+    #[arg(short, long, help = "Recursive directory processing")]
+    pub(crate) recursive: bool,
+
+    #[arg(long, help = "Also process dot-prefixed files and directories")]
+    pub(crate) include_hidden: bool,
+
+    #[arg(
+        long,
+        value_name = "NAME_OR_PATH",
+        help = "Skip directories with this name (e.g. node_modules), or at this path relative to an input; repeatable"
+    )]
+    pub(crate) exclude_dir: Vec<PathBuf>,
+
+    #[arg(
+        long,
+        value_name = "DURATION|DATE",
+        value_parser = parse_since,
+        help = "Only process files modified after this: an age (30m, 12h, 7d, 2w) or a UTC date (2024-01-01)"
+    )]
+    pub(crate) since: Option<SystemTime>,
+
+    #[arg(
+        long,
+        value_name = "PATH",
+        help = "Write a SHA-256 manifest of outputs (JSON, or text when PATH ends in .txt)"
+    )]
+    pub(crate) manifest: Option<PathBuf>,
+
+    #[arg(
+        long,
+        value_name = "PATH",
+        help = "Append one JSON line per completed file to PATH as it finishes, so a crashed run still leaves a log"
+    )]
+    pub(crate) jsonl: Option<PathBuf>,
+
+    #[arg(long, help = "Print how long decode, resize, encode and write took, summed across threads")]
+    pub(crate) profile: bool,
+
+    #[arg(
+        long,
+        help = "Skip inputs whose output already exists, is valid and is newer than the source"
+    )]
+    pub(crate) resume: bool,
+
+    #[arg(
+        long,
+        default_value = "error",
+        help = "What to do when two inputs would write the same output name"
+    )]
+    pub(crate) on_collision: CollisionPolicy,
+
+    #[arg(
+        long,
+        value_name = "START",
+        num_args = 0..=1,
+        default_missing_value = "1",
+        help = "Name outputs 1, 2, 3, ... (zero-padded to fit the count) in sorted input order"
+    )]
+    pub(crate) number: Option<u64>,
+
+    #[arg(
+        long,
+        conflicts_with_all = ["number", "dedupe", "interactive"],
+        help = "Compress files as the directory walk finds them instead of listing everything first"
+    )]
+    pub(crate) stream: bool,
+
+    #[arg(
+        long,
+        value_name = "MODE",
+        num_args = 0..=1,
+        default_missing_value = "skip",
+        help = "Compress byte-identical inputs once; skip or link the duplicates"
+    )]
+    pub(crate) dedupe: Option<DedupeMode>,
+
+    #[arg(
+        long,
+        value_name = "MP",
+        help = "Downscale images larger than this many megapixels (e.g. 12)"
+    )]
+    pub(crate) max_pixels: Option<f64>,
+
+    #[arg(long, help = "Maximum output width in pixels")]
+    pub(crate) max_width: Option<u32>,
+
+    #[arg(long, help = "Maximum output height in pixels")]
+    pub(crate) max_height: Option<u32>,
+
+    #[arg(
+        long,
+        default_value = "contain",
+        help = "How to fit --max-width and --max-height when both are given"
+    )]
+    pub(crate) resize_mode: ResizeMode,
+
+    #[arg(
+        long,
+        value_delimiter = ',',
+        conflicts_with_all = ["max_width", "resume", "interactive"],
+        help = "Emit one output per width (name-480w.ext, ...) plus a srcset.json manifest"
+    )]
+    pub(crate) widths: Vec<u32>,
+
+    #[arg(
+        long,
+        value_name = "SIGMA",
+        num_args = 0..=1,
+        default_missing_value = "0.8",
+        help = "Apply an unsharp mask after resizing (blur sigma, default 0.8)"
+    )]
+    pub(crate) sharpen: Option<f32>,
+
+    #[arg(
+        long,
+        default_value = "2",
+        help = "Minimum brightness difference the unsharp mask will enhance"
+    )]
+    pub(crate) sharpen_threshold: i32,
+
+    #[arg(long, value_name = "VALUE", help = "Gamma-correct the decoded image; above 1 brightens midtones, below 1 darkens them (0.1-10)")]
+    pub(crate) gamma: Option<f32>,
+
+    #[arg(long, value_name = "-100-100", allow_hyphen_values = true, help = "Shift brightness by this percentage of full scale")]
+    pub(crate) brightness: Option<f32>,
+
+    #[arg(long, value_name = "-100-100", allow_hyphen_values = true, help = "Stretch (positive) or flatten (negative) contrast around mid-gray, in percent")]
+    pub(crate) contrast: Option<f32>,
+
+    #[arg(long, help = "Break down the summary per immediate subdirectory")]
+    pub(crate) group_by_dir: bool,
+
+    #[arg(long, help = "Print the full path and reason for every failed file")]
+    pub(crate) list_errors: bool,
+
+    #[arg(long, help = "Exit nonzero if an input file isn't a supported image or any image fails to decode")]
+    pub(crate) fail_on_unsupported: bool,
+
+    #[arg(
+        long,
+        value_name = "BITS",
+        num_args = 0..=1,
+        default_missing_value = "10",
+        help = "Report clusters of visually similar images (dHash distance up to BITS of 64, default 10)"
+    )]
+    pub(crate) find_similar: Option<u32>,
+
+    #[arg(
+        long,
+        value_delimiter = ',',
+        help = "Extra columns for the results table (ratio, time)"
+    )]
+    pub(crate) columns: Vec<ResultColumn>,
+
+    #[arg(
+        long,
+        help = "Drop ancillary PNG chunks (text, EXIF, timestamps); on by default unless --keep-metadata"
+    )]
+    pub(crate) strip_chunks: bool,
+
+    #[command(flatten)]
+    pub(crate) encoder: EncoderArgs,
+
+    #[arg(
+        long,
+        help = "Show the projected size of each file and ask before writing it (runs serially)"
+    )]
+    pub(crate) interactive: bool,
+
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        help = "Give up on any single image that takes longer than this to decode and encode"
+    )]
+    pub(crate) timeout: Option<u64>,
+
+    #[arg(
+        long,
+        value_name = "N",
+        help = "Refuse images whose header declares more than N pixels, before decoding them"
+    )]
+    pub(crate) max_decoded_pixels: Option<u64>,
+
+    #[arg(
+        long,
+        conflicts_with = "interactive",
+        help = "Write one JSON event per line to stderr (started, file, finished) instead of a progress bar"
+    )]
+    pub(crate) progress_json: bool,
+
+    #[arg(
+        long,
+        value_name = "BYTES",
+        value_parser = parse_byte_size,
+        help = "Stop starting new files once outputs total this many bytes (e.g. 50MB)"
+    )]
+    pub(crate) total_budget: Option<u64>,
+
+    #[arg(
+        long,
+        value_name = "BYTES/s",
+        value_parser = parse_rate,
+        help = "Cap how fast input bytes are read across all workers (e.g. 5M, 500K/s)"
+    )]
+    pub(crate) max_throughput: Option<u64>,
+
+    #[arg(
+        long,
+        value_name = "N",
+        help = "Let at most N workers read source files at once; decoding and encoding stay parallel"
+    )]
+    pub(crate) max_concurrent_reads: Option<usize>,
+
+    #[arg(
+        long,
+        value_name = "N",
+        num_args = 0..=1,
+        default_missing_value = "10",
+        allow_negative_numbers = true,
+        help = "Run at a lower CPU priority (niceness N, default 10; Unix only)"
+    )]
+    pub(crate) nice: Option<i32>,
+
+    #[arg(long, help = "Only write an output if it is smaller than the file already at its path")]
+    pub(crate) only_if_smaller: bool,
+
+    #[arg(
+        long,
+        conflicts_with = "widths",
+        help = "Fall back to a copy of the source whenever the encode is larger, and report the file as an error"
+    )]
+    pub(crate) no_grow: bool,
+
+    #[arg(long, help = "Copy the source file's permission bits onto each output")]
+    pub(crate) preserve_mode: bool,
+
+    #[arg(long, help = "Copy the source file's access and modification times onto each output")]
+    pub(crate) preserve_timestamps: bool,
+
+    #[arg(long, help = "Treat flags that have no effect on the chosen format as errors")]
+    pub(crate) strict: bool,
+
+    #[arg(long, help = "Print every option as it takes effect, given or defaulted, as JSON before the run")]
+    pub(crate) print_settings: bool,
+
+    #[arg(long, help = "Print the files the walk and filters match, one per line, and exit without compressing")]
+    pub(crate) list_only: bool,
+
+    #[arg(
+        long,
+        value_name = "TEMPLATE",
+        help = "Run this command on every output after writing it; {} is replaced by the output path"
+    )]
+    pub(crate) post_cmd: Option<String>,
+
+    #[arg(
+        long,
+        conflicts_with = "keep_icc",
+        help = "Convert images with an embedded ICC profile to sRGB before encoding"
+    )]
+    pub(crate) to_srgb: bool,
+
+    #[arg(long, help = "Carry the source's ICC profile over unchanged into PNG, JPEG and WebP outputs")]
+    pub(crate) keep_icc: bool,
+
+    #[arg(
+        long,
+        value_name = "N",
+        default_value = "0",
+        help = "Retry reads and writes that fail with a transient I/O error up to N times"
+    )]
+    pub(crate) retry: u32,
+
+    #[arg(
+        long,
+        value_name = "MS",
+        default_value = "200",
+        help = "Wait this long before the first retry, doubling on each further attempt"
+    )]
+    pub(crate) retry_delay: u64,
+
+    #[arg(
+        long,
+        value_name = "MODE",
+        num_args = 0..=1,
+        default_missing_value = "print",
+        conflicts_with = "widths",
+        help = "After compressing a single file, print or open the original and output for a visual check"
+    )]
+    pub(crate) preview: Option<PreviewMode>,
+
+    #[arg(
+        long,
+        value_name = "N",
+        help = "Stamp N dots per inch into the output's resolution metadata (JPEG, PNG, BMP)"
+    )]
+    pub(crate) dpi: Option<u16>,
+}
+
+impl Args {
+    /// Parses `compress` options the way the command line spells them,
+    /// program name first: `["pixelsqueeze", "photos", "-f", "webp"]`.
+    pub fn try_parse_from<I, T>(args: I) -> Result<Self, clap::Error>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<std::ffi::OsString> + Clone,
+    {
+        let command = <Self as clap::Args>::augment_args(clap::Command::new("pixelsqueeze"));
+        Self::from_arg_matches(&command.try_get_matches_from(args)?)
+    }
+
+    /// Where `job`'s outputs go: next to the source under `--output-same-dir`,
+    /// the mirrored subdirectory under `--input-root` or
+    /// `--keep-structure-depth`, else the run's output directory. Mirrored
+    /// directories are created on first use unless `--no-dir-create`.
+    pub(crate) fn prepare_output_dir(&self, job: &Job, output_dir: &Path) -> Result<PathBuf> {
+        if self.output_same_dir {
+            return Ok(job.input.parent().unwrap_or(output_dir).to_path_buf());
+        }
+        let Some(subdir) = self.mirrored_subdir(&job.input)? else {
+            return Ok(output_dir.to_path_buf());
+        };
+
+        let dir = output_dir.join(subdir);
+        if self.no_dir_create {
+            if !dir.is_dir() {
+                anyhow::bail!("Output directory does not exist: {} (not created under --no-dir-create)", dir.display());
+            }
+        } else {
+            fs::create_dir_all(&dir)
+                .with_context(|| format!("Failed to create output directory: {}", dir.display()))?;
+        }
+        Ok(dir)
+    }
+
+    /// The source's directory below `--input-root` (or the input it was found
+    /// under), cut to `--keep-structure-depth` levels; `None` when outputs are
+    /// not mirrored.
+    pub(crate) fn mirrored_subdir(&self, input: &Path) -> Result<Option<PathBuf>> {
+        if self.input_root.is_none() && self.keep_structure_depth.is_none() {
+            return Ok(None);
+        }
+        let relative = match &self.input_root {
+            Some(root) => relative_to_root(input, root).with_context(|| {
+                format!("{} is outside --input-root {}", input.display(), root.display())
+            })?,
+            None => self.input.iter()
+                .find_map(|root| relative_to_root(input, root))
+                .unwrap_or_default(),
+        };
+        let depth = self.keep_structure_depth.unwrap_or(usize::MAX);
+        let subdir = relative.parent()
+            .map(|parent| parent.components().take(depth).collect())
+            .unwrap_or_default();
+        Ok(Some(subdir))
+    }
+
+    /// The directory whose outputs `input`'s outputs could clash with, when
+    /// outputs are not all written into one directory.
+    pub(crate) fn collision_dir(&self, input: &Path) -> Option<PathBuf> {
+        if self.output_same_dir {
+            return input.parent().map(Path::to_path_buf);
+        }
+        self.mirrored_subdir(input).ok().flatten()
+    }
+
+    /// What `--full-paths` shows paths relative to.
+    pub(crate) fn display_roots(&self) -> &[PathBuf] {
+        match &self.input_root {
+            Some(root) => std::slice::from_ref(root),
+            None => &self.input,
+        }
+    }
+
+    /// The output format for `input`: its `--map` entry, else `--format`.
+    pub(crate) fn format_for(&self, input: &Path) -> &OutputFormat {
+        let detected = image::ImageFormat::from_path(input).ok();
+        self.map.iter()
+            .find(|mapping| Some(mapping.input) == detected)
+            .map_or(&self.format, |mapping| &mapping.output)
+    }
+
+    /// `--quality`, or under `--auto-quality` a point in the band set by how
+    /// much detail the decoded image carries.
+    pub(crate) fn quality_for(&self, img: &image::DynamicImage) -> Option<f32> {
+        match self.auto_quality {
+            Some(QualityBand { min, max }) => {
+                let span = f64::from(max - min);
+                Some(f32::from(min + (span * image_complexity(img)).round() as u8))
+            }
+            None => self.quality,
+        }
+    }
+
+    /// Every format this run may write.
+    pub(crate) fn output_formats(&self) -> Vec<OutputFormat> {
+        std::iter::once(&self.format)
+            .chain(self.map.iter().map(|mapping| &mapping.output))
+            .cloned()
+            .collect()
+    }
+}
+
+/// The `--auto-quality` range.
+#[derive(Clone, Copy)]
+pub(crate) struct QualityBand {
+    pub(crate) min: u8,
+    pub(crate) max: u8,
+}
+
+/// One `--map` entry.
+#[derive(Clone)]
+pub(crate) struct FormatMapping {
+    pub(crate) input: image::ImageFormat,
+    pub(crate) output: OutputFormat,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+pub(crate) enum CollisionPolicy {
+    /// Refuse to start and list the conflicting inputs
+    Error,
+    /// Append a numeric suffix (-2, -3, ...) to later inputs
+    Rename,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+pub(crate) enum PreviewMode {
+    /// Print the original and output paths
+    Print,
+    /// Also open both in the system image viewer
+    Open,
+    /// Draw a small before/after strip in the terminal (sixel, or iTerm2's protocol)
+    #[cfg(feature = "inline-preview")]
+    Inline,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+pub(crate) enum DedupeMode {
+    /// Leave duplicates out of the output
+    Skip,
+    /// Hard-link (or copy) the first occurrence's output under each duplicate's name
+    Link,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+pub(crate) enum ResizeMode {
+    /// Fit inside the box, keeping the aspect ratio
+    Contain,
+    /// Fill the box, center-cropping the overflow
+    Cover,
+    /// Stretch to the exact box dimensions
+    Fill,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub(crate) enum ResultColumn {
+    /// Original size divided by compressed size
+    Ratio,
+    /// Wall time spent decoding and encoding the file
+    Time,
+}
+
+/// The format an output is encoded in.
+#[derive(Clone, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    Jpeg,
+    Png,
+    Webp,
+    Ico,
+    Bmp,
+    Tga,
+    /// Palette GIF (first frame only), quantized like --png-colors
+    Gif,
+    /// Encode JPEG, PNG and WebP and keep whichever is smallest
+    Best,
+    /// PNG for images with few colors, WebP for photographic ones; one encode
+    Auto,
+}
+
+impl OutputFormat {
+    /// The format itself, unless it is `best` or `auto` and only settled by encoding.
+    pub(crate) const fn concrete(&self) -> Option<ConcreteFormat> {
+        match self {
+            Self::Jpeg => Some(ConcreteFormat::Jpeg),
+            Self::Png => Some(ConcreteFormat::Png),
+            Self::Webp => Some(ConcreteFormat::Webp),
+            Self::Ico => Some(ConcreteFormat::Ico),
+            Self::Bmp => Some(ConcreteFormat::Bmp),
+            Self::Tga => Some(ConcreteFormat::Tga),
+            Self::Gif => Some(ConcreteFormat::Gif),
+            Self::Best | Self::Auto => None,
+        }
+    }
+
+    /// Quality used when `--quality` is not given, picked so JPEG and WebP
+    /// land at a similar perceived quality; the other formats ignore it.
+    pub(crate) const fn default_quality(&self) -> f32 {
+        match self {
+            Self::Webp => 80.0,
+            _ => 75.0,
+        }
+    }
+
+    /// The concrete formats an output in this format may end up as.
+    pub(crate) const fn candidates(&self) -> &'static [ConcreteFormat] {
+        match self {
+            Self::Jpeg => &[ConcreteFormat::Jpeg],
+            Self::Png => &[ConcreteFormat::Png],
+            Self::Webp => &[ConcreteFormat::Webp],
+            Self::Ico => &[ConcreteFormat::Ico],
+            Self::Bmp => &[ConcreteFormat::Bmp],
+            Self::Tga => &[ConcreteFormat::Tga],
+            Self::Gif => &[ConcreteFormat::Gif],
+            Self::Best => &[ConcreteFormat::Jpeg, ConcreteFormat::Png, ConcreteFormat::Webp],
+            Self::Auto => &[ConcreteFormat::Png, ConcreteFormat::Webp],
+        }
+    }
+
+    /// Formats picked for compatibility rather than size, where growth is
+    /// expected and a copy of the source would not satisfy the request.
+    pub(crate) const fn expects_growth(&self) -> bool {
+        matches!(self, Self::Ico | Self::Bmp | Self::Tga | Self::Gif)
+    }
+}
+
+/// The format an output is written in, once `best` and `auto` are settled.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum ConcreteFormat {
+    Jpeg,
+    Png,
+    Webp,
+    Ico,
+    Bmp,
+    Tga,
+    Gif,
+}
+
+impl ConcreteFormat {
+    pub(crate) const fn extension(self) -> &'static str {
+        match self {
+            Self::Jpeg => "jpg",
+            Self::Png => "png",
+            Self::Webp => "webp",
+            Self::Ico => "ico",
+            Self::Bmp => "bmp",
+            Self::Tga => "tga",
+            Self::Gif => "gif",
+        }
+    }
+
+    pub(crate) const fn image_format(self) -> image::ImageFormat {
+        match self {
+            Self::Jpeg => image::ImageFormat::Jpeg,
+            Self::Png => image::ImageFormat::Png,
+            Self::Webp => image::ImageFormat::WebP,
+            Self::Ico => image::ImageFormat::Ico,
+            Self::Bmp => image::ImageFormat::Bmp,
+            Self::Tga => image::ImageFormat::Tga,
+            Self::Gif => image::ImageFormat::Gif,
+        }
+    }
+}
+
+impl From<ConcreteFormat> for OutputFormat {
+    fn from(format: ConcreteFormat) -> Self {
+        match format {
+            ConcreteFormat::Jpeg => Self::Jpeg,
+            ConcreteFormat::Png => Self::Png,
+            ConcreteFormat::Webp => Self::Webp,
+            ConcreteFormat::Ico => Self::Ico,
+            ConcreteFormat::Bmp => Self::Bmp,
+            ConcreteFormat::Tga => Self::Tga,
+            ConcreteFormat::Gif => Self::Gif,
+        }
+    }
+}
+
+/// `--print-settings`: the subcommand's options keyed by flag name, with
+/// defaults filled in and unset options as null, so a run can be repeated
+/// exactly. Values stay in their command-line spelling.
+pub(crate) fn print_settings(subcommand: &str, matches: &ArgMatches) -> Result<()> {
+    use clap::ArgAction;
+    use serde_json::Value;
+
+    let command = Cli::command();
+    let arguments = command.find_subcommand(subcommand)
+        .map(|sub| sub.get_arguments().cloned().collect::<Vec<_>>())
+        .unwrap_or_default();
+
+    let mut settings = serde_json::Map::new();
+    for arg in &arguments {
+        if matches!(arg.get_action(), ArgAction::Help | ArgAction::HelpShort | ArgAction::HelpLong | ArgAction::Version) {
+            continue;
+        }
+        let id = arg.get_id().as_str();
+        let name = arg.get_long().unwrap_or(id).to_string();
+        let raw: Option<Vec<String>> = matches.get_raw(id)
+            .map(|values| values.map(|value| value.to_string_lossy().into_owned()).collect());
+
+        let value = match (arg.get_action(), raw) {
+            (ArgAction::SetTrue | ArgAction::SetFalse, _) => Value::Bool(matches.get_flag(id)),
+            (ArgAction::Append, raw) => Value::from(raw.unwrap_or_default()),
+            (_, Some(mut values)) if values.len() == 1 => Value::from(values.remove(0)),
+            (_, Some(values)) => Value::from(values),
+            (_, None) => Value::Null,
+        };
+        settings.insert(name, value);
+    }
+    // Resolved here rather than by clap, so not among the options
+    settings.insert("threads".to_string(), Value::from(num_cpus::get().max(1)));
+
+    println!("{}", serde_json::to_string_pretty(&Value::Object(settings))?);
+    Ok(())
+}
+
+/// Ids of the arguments typed on the command line, as opposed to defaults.
+pub(crate) fn given_flags(matches: &ArgMatches) -> Vec<String> {
+    matches.ids()
+        .filter(|id| matches.value_source(id.as_str()) == Some(ValueSource::CommandLine))
+        .map(|id| id.to_string())
+        .collect()
+}
+
+/// Warns about flags that have no effect with the chosen format or the other
+/// flags given, or refuses to run under `--strict`.
+pub(crate) fn check_format_flags(formats: &[OutputFormat], given: &[String], strict: bool, progress_json: bool) -> Result<()> {
+    let is_given = |id: &str| given.iter().any(|g| g == id);
+    let writes = |wanted: &[OutputFormat]| {
+        formats.iter().any(|format| wanted.contains(format))
+    };
+    let quantizes = writes(&[OutputFormat::Gif])
+        || ((is_given("png_colors") || is_given("png_bit_depth"))
+            && writes(&[OutputFormat::Png, OutputFormat::Best, OutputFormat::Auto]));
+
+    let problems: Vec<&str> = given.iter()
+        .filter_map(|id| match id.as_str() {
+            "quality" if !writes(&[OutputFormat::Jpeg, OutputFormat::Webp, OutputFormat::Best, OutputFormat::Auto]) => {
+                Some("--quality only affects jpeg and webp output")
+            }
+            "quality" if is_given("lossless") => Some("--quality is ignored with --lossless"),
+            "auto_quality" if !writes(&[OutputFormat::Jpeg, OutputFormat::Webp, OutputFormat::Best, OutputFormat::Auto]) => {
+                Some("--auto-quality only affects jpeg and webp output")
+            }
+            "auto_quality" if is_given("lossless") => Some("--auto-quality is ignored with --lossless"),
+            "quality_steps" if !writes(&[OutputFormat::Jpeg, OutputFormat::Webp, OutputFormat::Best, OutputFormat::Auto]) => {
+                Some("--quality-steps only affects jpeg and webp output")
+            }
+            "quality_steps" if is_given("lossless") => Some("--quality-steps is ignored with --lossless"),
+            "quality_floor" if !is_given("quality_steps") => Some("--quality-floor has no effect without --quality-steps"),
+            "png_colors" if !writes(&[OutputFormat::Png, OutputFormat::Gif, OutputFormat::Best, OutputFormat::Auto]) => {
+                Some("--png-colors only affects png and gif output")
+            }
+            "dither" | "dither_level" if !quantizes => {
+                Some("--dither and --dither-level only affect palette output (--png-colors, --png-bit-depth or --format gif)")
+            }
+            "png_interlace" | "png_compression" | "png_filter" | "png_bit_depth"
+                if !writes(&[OutputFormat::Png, OutputFormat::Best, OutputFormat::Auto]) =>
+            {
+                Some("--png-interlace, --png-compression, --png-filter and --png-bit-depth only affect png output")
+            }
+            "jpeg_optimize" if !writes(&[OutputFormat::Jpeg, OutputFormat::Best]) => {
+                Some("--jpeg-optimize only affects jpeg output")
+            }
+            "keep_icc"
+                if !writes(&[OutputFormat::Jpeg, OutputFormat::Png, OutputFormat::Webp, OutputFormat::Best, OutputFormat::Auto]) =>
+            {
+                Some("--keep-icc only affects jpeg, png and webp output")
+            }
+            "webp_alpha" | "webp_alpha_quality"
+                if !writes(&[OutputFormat::Webp, OutputFormat::Best, OutputFormat::Auto]) =>
+            {
+                Some("--webp-alpha and --webp-alpha-quality only affect webp output")
+            }
+            "webp_alpha" | "webp_alpha_quality" if is_given("lossless") => {
+                Some("--webp-alpha and --webp-alpha-quality are ignored with --lossless")
+            }
+            "ico_sizes" if !writes(&[OutputFormat::Ico]) => Some("--ico-sizes only affects ico output"),
+            "retry_delay" if !is_given("retry") => Some("--retry-delay has no effect without --retry"),
+            "strip_thumbnail" if !is_given("keep_metadata") => {
+                Some("--strip-thumbnail has no effect without --keep-metadata")
+            }
+            "sharpen_threshold" if !is_given("sharpen") => Some("--sharpen-threshold has no effect without --sharpen"),
+            "resize_mode" if !(is_given("max_width") && is_given("max_height")) => {
+                Some("--resize-mode only matters when both --max-width and --max-height are given")
+            }
+            _ => None,
+        })
+        .collect();
+
+    if strict && !problems.is_empty() {
+        anyhow::bail!("{} (rejected by --strict)", problems.join("; "));
+    }
+    for problem in problems {
+        print_warning(problem, progress_json);
+    }
+    Ok(())
+}
+
+/// Inserts `compress` when the first argument isn't a subcommand, so
+/// `pixelsqueeze photos/` keeps working as before.
+pub(crate) fn with_default_subcommand(args: impl Iterator<Item = std::ffi::OsString>) -> Vec<std::ffi::OsString> {
+    let mut args: Vec<_> = args.collect();
+    let needs_default = args.get(1).is_some_and(|first| {
+        let first = first.to_string_lossy();
+        !SUBCOMMANDS.contains(&first.as_ref())
+            && !matches!(first.as_ref(), "-h" | "--help" | "-V" | "--version")
+    });
+    if needs_default {
+        args.insert(1, "compress".into());
+    }
+    args
+}
+
+/// Shared by every command that takes `--quality`; written to fail for NaN too.
+pub(crate) fn is_valid_quality(quality: f32) -> bool {
+    quality > 0.0 && quality <= 100.0
+}
+
+pub(crate) fn validate_args(args: &Args) -> Result<()> {
+    if !args.quality.is_none_or(is_valid_quality) {
+        anyhow::bail!("Quality must be above 0 and at most 100");
+    }
+    for format in args.output_formats() {
+        validate_encoder_args(&args.encoder, &format)?;
+    }
+    if args.quality_steps.is_some_and(|step| !(step > 0.0 && step <= 100.0)) {
+        anyhow::bail!("--quality-steps must be above 0 and at most 100");
+    }
+    if !(args.quality_floor > 0.0 && args.quality_floor <= 100.0) {
+        anyhow::bail!("--quality-floor must be above 0 and at most 100");
+    }
+    if args.sharpen.is_some_and(|sigma| sigma.is_nan() || sigma <= 0.0) {
+        anyhow::bail!("--sharpen sigma must be greater than 0");
+    }
+    if args.sharpen_threshold < 0 {
+        anyhow::bail!("--sharpen-threshold must not be negative");
+    }
+    if args.gamma.is_some_and(|gamma| !(0.1..=10.0).contains(&gamma)) {
+        anyhow::bail!("--gamma must be between 0.1 and 10");
+    }
+    if args.brightness.is_some_and(|value| !(-100.0..=100.0).contains(&value)) {
+        anyhow::bail!("--brightness must be between -100 and 100");
+    }
+    if args.contrast.is_some_and(|value| !(-100.0..=100.0).contains(&value)) {
+        anyhow::bail!("--contrast must be between -100 and 100");
+    }
+    if args.max_decoded_pixels == Some(0) {
+        anyhow::bail!("--max-decoded-pixels must be greater than 0");
+    }
+    if args.preview.is_some() && !matches!(&args.input[..], [input] if input.is_file()) {
+        anyhow::bail!("--preview needs a single input file");
+    }
+    if args.max_concurrent_reads == Some(0) {
+        anyhow::bail!("--max-concurrent-reads must be at least 1");
+    }
+    if args.timeout == Some(0) {
+        anyhow::bail!("--timeout must be at least 1 second");
+    }
+    if args.widths.contains(&0) {
+        anyhow::bail!("--widths must all be greater than 0");
+    }
+    if args.max_width == Some(0) || args.max_height == Some(0) {
+        anyhow::bail!("--max-width and --max-height must be greater than 0");
+    }
+    if args.max_pixels.is_some_and(|mp| mp.is_nan() || mp <= 0.0) {
+        anyhow::bail!("--max-pixels must be greater than 0");
+    }
+    if args.post_cmd.as_ref().is_some_and(|template| template.trim().is_empty()) {
+        anyhow::bail!("--post-cmd must not be empty");
+    }
+    if args.dpi == Some(0) {
+        anyhow::bail!("--dpi must be greater than 0");
+    }
+    // The walk passes over these silently, which a strict run shouldn't
+    if args.fail_on_unsupported
+        && let Some(path) = args.input.iter().find(|path| path.is_file() && !is_image_file(path))
+    {
+        anyhow::bail!("Not a supported image: {} (rejected by --fail-on-unsupported)", path.display());
+    }
+    if args.input_root.as_ref().is_some_and(|root| !root.is_dir()) {
+        anyhow::bail!("--input-root must be an existing directory");
+    }
+    if args.suffix.as_ref().is_some_and(|suffix| suffix.is_empty() || suffix.contains(['/', '\\'])) {
+        anyhow::bail!("--suffix must be non-empty and must not contain path separators");
+    }
+    if args.dpi.is_some()
+        && args.output_formats().iter().any(|format| {
+            // best and auto can settle on WebP, which would drop the density
+            matches!(format, OutputFormat::Webp | OutputFormat::Ico | OutputFormat::Tga | OutputFormat::Gif | OutputFormat::Best | OutputFormat::Auto)
+        })
+    {
+        anyhow::bail!("--dpi needs an output format with resolution metadata (jpeg, png or bmp), not best or auto");
+    }
+    Ok(())
+}
+
+pub(crate) fn validate_encoder_args(encoder: &EncoderArgs, format: &OutputFormat) -> Result<()> {
+    if encoder.ico_sizes.is_empty() || encoder.ico_sizes.iter().any(|&size| !(1..=256).contains(&size)) {
+        anyhow::bail!("--ico-sizes must be between 1 and 256");
+    }
+    if encoder.png_colors.is_some_and(|colors| !(2..=256).contains(&colors)) {
+        anyhow::bail!("--png-colors must be between 2 and 256");
+    }
+    if encoder.png_bit_depth.is_some_and(|bits| !(1..=8).contains(&bits)) {
+        anyhow::bail!("--png-bit-depth must be between 1 and 8");
+    }
+    if !(0.0..=1.0).contains(&encoder.dither_level) {
+        anyhow::bail!("--dither-level must be between 0 and 1");
+    }
+    if encoder.webp_alpha_quality > 100 {
+        anyhow::bail!("--webp-alpha-quality must be between 0 and 100");
+    }
+    if encoder.lossless {
+        match format {
+            OutputFormat::Jpeg => anyhow::bail!("JPEG has no lossless mode; use --format png, webp or best with --lossless"),
+            OutputFormat::Gif => anyhow::bail!("GIF output is palette-quantized; use --format png or webp with --lossless"),
+            _ if encoder.png_colors.is_some() => anyhow::bail!("--png-colors quantizes and cannot be combined with --lossless"),
+            _ if encoder.png_bit_depth.is_some() => anyhow::bail!("--png-bit-depth posterizes and cannot be combined with --lossless"),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Parses sizes like `750000`, `500K`, `5MB` or `2GiB`. K, M and G are
+/// decimal (as in the results table); KiB, MiB and GiB are binary.
+pub(crate) fn parse_byte_size(value: &str) -> Result<u64, String> {
+    let value = value.trim();
+    let split = value.find(|c: char| !(c.is_ascii_digit() || c == '.')).unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let number: f64 = number.parse().map_err(|_| format!("invalid size: {}", value))?;
+    let multiplier: u64 = match unit.trim().to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "k" | "kb" => 1_000,
+        "m" | "mb" => 1_000_000,
+        "g" | "gb" => 1_000_000_000,
+        "kib" => 1 << 10,
+        "mib" => 1 << 20,
+        "gib" => 1 << 30,
+        _ => return Err(format!("unknown size unit in {}; use K, M, G, KiB, MiB or GiB", value)),
+    };
+    let bytes = (number * multiplier as f64).round();
+    if bytes < 1.0 {
+        return Err("size must be at least 1 byte".to_string());
+    }
+    Ok(bytes as u64)
+}
+
+/// `--since`: an age before now (`30m`, `12h`, `7d`, `2w`) or a UTC date,
+/// optionally with a time (`2024-01-01`, `2024-01-01T08:30:00`).
+pub(crate) fn parse_since(value: &str) -> Result<SystemTime, String> {
+    let value = value.trim();
+    if let Some(unit) = value.chars().last().filter(char::is_ascii_alphabetic)
+        && let Ok(count) = value[..value.len() - 1].parse::<u64>()
+    {
+        let seconds = match unit.to_ascii_lowercase() {
+            's' => 1,
+            'm' => 60,
+            'h' => 60 * 60,
+            'd' => 24 * 60 * 60,
+            'w' => 7 * 24 * 60 * 60,
+            _ => return Err(format!("unknown duration unit in {}; use s, m, h, d or w", value)),
+        };
+        return SystemTime::now()
+            .checked_sub(Duration::from_secs(count.saturating_mul(seconds)))
+            .ok_or_else(|| format!("{} reaches back too far", value));
+    }
+
+    let invalid = || format!("expected a duration (7d) or a date (2024-01-01), got '{}'", value);
+    let (date, time) = value.split_once(['T', ' ']).unwrap_or((value, "00:00:00"));
+    let fields = |text: &str, separator: char| -> Option<Vec<u32>> {
+        text.split(separator).map(|field| field.parse().ok()).collect()
+    };
+    let (date, time) = (fields(date, '-'), fields(time, ':'));
+    let (year, month, day, hour, minute, second) = match (date.as_deref(), time.as_deref()) {
+        (Some(&[year, month, day]), Some(&[hour, minute])) => (year, month, day, hour, minute, 0),
+        (Some(&[year, month, day]), Some(&[hour, minute, second])) => (year, month, day, hour, minute, second),
+        _ => return Err(invalid()),
+    };
+    if !(1..=12).contains(&month) || !(1..=days_in_month(year, month)).contains(&day) || hour > 23 || minute > 59 || second > 59 {
+        return Err(invalid());
+    }
+
+    let days = days_from_civil(i64::from(year), month, day);
+    let seconds = days * 86_400 + i64::from(hour * 3600 + minute * 60 + second);
+    let since_epoch = Duration::from_secs(seconds.unsigned_abs());
+    let time = if seconds >= 0 {
+        SystemTime::UNIX_EPOCH.checked_add(since_epoch)
+    } else {
+        SystemTime::UNIX_EPOCH.checked_sub(since_epoch)
+    };
+    time.ok_or_else(invalid)
+}
+
+fn days_in_month(year: u32, month: u32) -> u32 {
+    match month {
+        2 if year.is_multiple_of(4) && (!year.is_multiple_of(100) || year.is_multiple_of(400)) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+/// Days from 1970-01-01 to a proleptic Gregorian date (Howard Hinnant's algorithm).
+pub(crate) fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month_index = i64::from((month + 9) % 12);
+    let day_of_year = (153 * month_index + 2) / 5 + i64::from(day) - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+pub(crate) fn parse_format_mapping(value: &str) -> Result<FormatMapping, String> {
+    let (input, output) = value.split_once('=')
+        .ok_or_else(|| format!("expected IN=OUT (e.g. jpg=webp), got '{}'", value))?;
+    let input = image::ImageFormat::from_extension(input.trim())
+        .ok_or_else(|| format!("unknown input format '{}'", input.trim()))?;
+    let output = OutputFormat::from_str(output.trim(), true)?;
+    Ok(FormatMapping { input, output })
+}
+
+pub(crate) fn parse_quality_band(value: &str) -> Result<QualityBand, String> {
+    let (min, max) = value.split_once('-')
+        .ok_or_else(|| format!("expected MIN-MAX (e.g. 40-85), got '{}'", value))?;
+    let parse = |q: &str| q.trim().parse::<u8>().ok().filter(|q| (1..=100).contains(q))
+        .ok_or_else(|| format!("'{}' is not a quality between 1 and 100", q.trim()));
+    let (min, max) = (parse(min)?, parse(max)?);
+    if min > max {
+        return Err(format!("band minimum {} is above its maximum {}", min, max));
+    }
+    Ok(QualityBand { min, max })
+}
+
+pub(crate) fn parse_rate(value: &str) -> Result<u64, String> {
+    let value = value.trim();
+    parse_byte_size(value.strip_suffix("/s").unwrap_or(value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unix_seconds(time: SystemTime) -> i64 {
+        match time.duration_since(SystemTime::UNIX_EPOCH) {
+            Ok(after) => after.as_secs() as i64,
+            Err(before) => -(before.duration().as_secs() as i64),
+        }
+    }
+
+    #[test]
+    fn parse_byte_size_reads_decimal_and_binary_units() {
+        let cases = [
+            ("512", 512),
+            ("10b", 10),
+            ("1K", 1_000),
+            ("1.5kb", 1_500),
+            (" 2 MB ", 2_000_000),
+            ("3g", 3_000_000_000),
+            ("1KiB", 1_024),
+            ("1.5mib", 1_572_864),
+            ("2GiB", 2 << 30),
+            // Rounded to whole bytes
+            ("0.5", 1),
+            ("1.0005K", 1_001),
+        ];
+        for (value, bytes) in cases {
+            assert_eq!(parse_byte_size(value), Ok(bytes), "{value}");
+        }
+
+        let invalid = ["", "K", "abc", "-1", "1.2.3K", "1e6", "5TB", "5 K B", "0", "0.4", "10M/s"];
+        for value in invalid {
+            assert!(parse_byte_size(value).is_err(), "{value:?} was accepted");
+        }
+    }
+
+    #[test]
+    fn parse_rate_takes_optional_per_second_suffix() {
+        let cases = [("100", 100), ("10M/s", 10_000_000), ("64KiB/s", 65_536), (" 1.5 MB/s ", 1_500_000), ("2k", 2_000)];
+        for (value, bytes) in cases {
+            assert_eq!(parse_rate(value), Ok(bytes), "{value}");
+        }
+
+        let invalid = ["/s", "0/s", "10M/h", "10M//s", "10M/s/s", "10/S2"];
+        for value in invalid {
+            assert!(parse_rate(value).is_err(), "{value:?} was accepted");
+        }
+    }
+
+    #[test]
+    fn days_from_civil_counts_leap_days() {
+        let cases = [
+            ((1970, 1, 1), 0),
+            ((1969, 12, 31), -1),
+            ((2000, 3, 1), 11_017),
+            ((2024, 2, 29), 19_782),
+            ((2024, 3, 1), 19_783),
+            ((2100, 3, 1), 47_541),
+            ((1600, 1, 1), -135_140),
+        ];
+        for ((year, month, day), days) in cases {
+            assert_eq!(days_from_civil(year, month, day), days, "{year}-{month}-{day}");
+        }
+    }
+
+    #[test]
+    fn parse_since_reads_dates_with_and_without_time() {
+        let cases = [
+            ("2024-02-29", 1_709_164_800),
+            ("2024-02-29T08:30", 1_709_195_400),
+            ("2024-02-29 08:30:15", 1_709_195_415),
+            (" 2000-02-29T23:59:59 ", 951_868_799),
+            ("1969-12-31", -86_400),
+        ];
+        for (value, seconds) in cases {
+            assert_eq!(parse_since(value).map(unix_seconds), Ok(seconds), "{value}");
+        }
+
+        let week = unix_seconds(SystemTime::now()) - unix_seconds(parse_since("2w").unwrap());
+        assert!((14 * 86_400..14 * 86_400 + 5).contains(&week), "{week}");
+        let hours = unix_seconds(SystemTime::now()) - unix_seconds(parse_since("12H").unwrap());
+        assert!((12 * 3600..12 * 3600 + 5).contains(&hours), "{hours}");
+    }
+
+    #[test]
+    fn parse_since_rejects_invalid_input() {
+        let invalid = [
+            "", "yesterday", "7y", "2024", "2024-01", "2024-1-1-1", "2024-13-01", "2024-00-10", "2024-01-32",
+            "2023-02-29", "1900-02-29", "2024-04-31", "2024-01-01T24:00", "2024-01-01T08:60", "2024-01-01T08",
+            "2024-01-01T08:30:60", "2024-01-01T08:30:00Z", "-2024-01-01",
+        ];
+        for value in invalid {
+            assert!(parse_since(value).is_err(), "{value:?} was accepted");
+        }
+    }
+}
@@ -0,0 +1,501 @@
+//! Lossless JPEG re-encoding with Huffman tables built for the image.
+
+/// Rewrites a baseline JPEG with Huffman tables built from its own symbol
+/// frequencies (ITU T.81 Annex K.2), as `jpegtran -optimize` does. Only the
+/// entropy coding changes, so the result decodes to exactly the same pixels.
+/// Returns `None` for anything outside baseline Huffman coding (progressive,
+/// arithmetic) or a stream it can't follow, and the original is kept.
+pub(crate) fn optimize_jpeg_huffman(data: &[u8]) -> Option<Vec<u8>> {
+    if data.get(..2)? != [0xFF, 0xD8] {
+        return None;
+    }
+
+    let mut out = Vec::with_capacity(data.len());
+    out.extend_from_slice(&[0xFF, 0xD8]);
+    let mut pos = 2;
+    let mut tables: [Option<HuffmanTable>; 8] = Default::default();
+    let mut frame = None;
+    let mut restart_interval = 0;
+
+    loop {
+        if *data.get(pos)? != 0xFF {
+            return None;
+        }
+        // Any number of 0xFF fill bytes may precede a marker
+        while data.get(pos) == Some(&0xFF) {
+            pos += 1;
+        }
+        let marker = *data.get(pos)?;
+        pos += 1;
+
+        match marker {
+            0xD9 => {
+                out.extend_from_slice(&[0xFF, 0xD9]);
+                out.extend_from_slice(&data[pos..]);
+                return Some(out);
+            }
+            0x01 | 0xD0..=0xD7 => {
+                out.extend_from_slice(&[0xFF, marker]);
+                continue;
+            }
+            _ => {}
+        }
+
+        let length = usize::from(u16::from_be_bytes([*data.get(pos)?, *data.get(pos + 1)?]));
+        let segment = data.get(pos + 2..pos + length.checked_sub(2)? + 2)?;
+        let raw = &data[pos..pos + length];
+        pos += length;
+
+        match marker {
+            // Original tables are replaced by optimized ones in front of each scan
+            0xC4 => parse_huffman_tables(segment, &mut tables)?,
+            0xC0 | 0xC1 => {
+                frame = Some(JpegFrame::parse(segment)?);
+                out.extend_from_slice(&[0xFF, marker]);
+                out.extend_from_slice(raw);
+            }
+            0xC2 | 0xC3 | 0xC5..=0xC7 | 0xC9..=0xCB | 0xCD..=0xCF => return None,
+            0xDD => {
+                restart_interval = u16::from_be_bytes([*segment.first()?, *segment.get(1)?]);
+                out.extend_from_slice(&[0xFF, marker]);
+                out.extend_from_slice(raw);
+            }
+            0xDA => {
+                let frame = frame.as_ref()?;
+                let scan = JpegScan::parse(segment, frame)?;
+                let mut reader = BitReader { data, pos, byte: 0, bits: 0 };
+                let tokens = decode_scan(&mut reader, frame, &scan, &tables, restart_interval)?;
+                pos = reader.pos;
+
+                let codes = write_optimized_tables(&mut out, &scan, &tokens);
+                out.extend_from_slice(&[0xFF, 0xDA]);
+                out.extend_from_slice(raw);
+                encode_scan(&mut out, &tokens, &codes);
+            }
+            _ => {
+                out.extend_from_slice(&[0xFF, marker]);
+                out.extend_from_slice(raw);
+            }
+        }
+    }
+}
+
+/// A decoding table in canonical form: codes of each length are consecutive.
+#[derive(Clone)]
+pub(crate) struct HuffmanTable {
+    pub(crate) counts: [u8; 17],
+    pub(crate) first_code: [u32; 17],
+    pub(crate) first_index: [usize; 17],
+    pub(crate) values: Vec<u8>,
+}
+
+impl HuffmanTable {
+    pub(crate) fn new(counts: [u8; 17], values: Vec<u8>) -> Self {
+        let (mut first_code, mut first_index) = ([0; 17], [0; 17]);
+        let (mut code, mut index) = (0u32, 0usize);
+        for len in 1..=16 {
+            first_code[len] = code;
+            first_index[len] = index;
+            code = (code + u32::from(counts[len])) << 1;
+            index += usize::from(counts[len]);
+        }
+        Self { counts, first_code, first_index, values }
+    }
+
+    pub(crate) fn decode(&self, reader: &mut BitReader) -> Option<u8> {
+        let mut code = 0;
+        for len in 1..=16 {
+            code = (code << 1) | reader.bit()?;
+            let offset = code.wrapping_sub(self.first_code[len]);
+            if offset < u32::from(self.counts[len]) {
+                return self.values.get(self.first_index[len] + offset as usize).copied();
+            }
+        }
+        None
+    }
+}
+
+/// Tables are indexed 0-3 for DC and 4-7 for AC.
+pub(crate) fn parse_huffman_tables(mut segment: &[u8], tables: &mut [Option<HuffmanTable>; 8]) -> Option<()> {
+    while let Some((&class_id, rest)) = segment.split_first() {
+        let (class, id) = (usize::from(class_id >> 4), usize::from(class_id & 0x0F));
+        if class > 1 || id > 3 {
+            return None;
+        }
+        let mut counts = [0; 17];
+        counts[1..].copy_from_slice(rest.get(..16)?);
+        let total: usize = counts.iter().map(|&c| usize::from(c)).sum();
+        let values = rest.get(16..16 + total)?.to_vec();
+        tables[class * 4 + id] = Some(HuffmanTable::new(counts, values));
+        segment = &rest[16 + total..];
+    }
+    Some(())
+}
+
+pub(crate) struct JpegFrame {
+    pub(crate) width: usize,
+    pub(crate) height: usize,
+    /// Component id and horizontal/vertical sampling factors
+    pub(crate) components: Vec<(u8, usize, usize)>,
+}
+
+impl JpegFrame {
+    pub(crate) fn parse(segment: &[u8]) -> Option<Self> {
+        let height = usize::from(u16::from_be_bytes([*segment.get(1)?, *segment.get(2)?]));
+        let width = usize::from(u16::from_be_bytes([*segment.get(3)?, *segment.get(4)?]));
+        let count = usize::from(*segment.get(5)?);
+        let components = (0..count)
+            .map(|i| {
+                let component = segment.get(6 + i * 3..9 + i * 3)?;
+                let (h, v) = (usize::from(component[1] >> 4), usize::from(component[1] & 0x0F));
+                (1..=4).contains(&h).then_some(())?;
+                (1..=4).contains(&v).then_some(())?;
+                Some((component[0], h, v))
+            })
+            .collect::<Option<Vec<_>>>()?;
+        // A zero height means it comes later in a DNL marker
+        (width > 0 && height > 0 && !components.is_empty()).then_some(Self { width, height, components })
+    }
+
+    pub(crate) fn max_sampling(&self) -> (usize, usize) {
+        let h = self.components.iter().map(|c| c.1).max().unwrap_or(1);
+        let v = self.components.iter().map(|c| c.2).max().unwrap_or(1);
+        (h, v)
+    }
+}
+
+pub(crate) struct JpegScan {
+    /// Frame component index with its DC and AC table numbers
+    pub(crate) components: Vec<(usize, usize, usize)>,
+}
+
+impl JpegScan {
+    pub(crate) fn parse(segment: &[u8], frame: &JpegFrame) -> Option<Self> {
+        let count = usize::from(*segment.first()?);
+        let components = (0..count)
+            .map(|i| {
+                let selector = segment.get(1 + i * 2..3 + i * 2)?;
+                let index = frame.components.iter().position(|c| c.0 == selector[0])?;
+                let (dc, ac) = (usize::from(selector[1] >> 4), usize::from(selector[1] & 0x0F));
+                (dc < 4 && ac < 4).then_some((index, dc, ac))
+            })
+            .collect::<Option<Vec<_>>>()?;
+        // Sequential scans always cover the full spectrum at full precision
+        let spectral = segment.get(1 + count * 2..4 + count * 2)?;
+        (!components.is_empty() && spectral == [0, 63, 0]).then_some(Self { components })
+    }
+}
+
+/// One Huffman-coded symbol with the raw bits that follow it, or a restart marker.
+pub(crate) enum ScanToken {
+    Symbol { table: u8, symbol: u8, extra: u16, extra_len: u8 },
+    Restart,
+}
+
+pub(crate) struct BitReader<'a> {
+    pub(crate) data: &'a [u8],
+    pub(crate) pos: usize,
+    pub(crate) byte: u8,
+    pub(crate) bits: u8,
+}
+
+impl BitReader<'_> {
+    pub(crate) fn bit(&mut self) -> Option<u32> {
+        if self.bits == 0 {
+            let byte = *self.data.get(self.pos)?;
+            if byte == 0xFF {
+                // A stuffed zero follows literal 0xFF bytes; anything else is a marker
+                if *self.data.get(self.pos + 1)? != 0x00 {
+                    return None;
+                }
+                self.pos += 1;
+            }
+            self.pos += 1;
+            self.byte = byte;
+            self.bits = 8;
+        }
+        self.bits -= 1;
+        Some(u32::from(self.byte >> self.bits) & 1)
+    }
+
+    pub(crate) fn bits(&mut self, count: u8) -> Option<u16> {
+        (0..count).try_fold(0u16, |value, _| Some((value << 1) | self.bit()? as u16))
+    }
+}
+
+pub(crate) fn decode_scan(
+    reader: &mut BitReader,
+    frame: &JpegFrame,
+    scan: &JpegScan,
+    tables: &[Option<HuffmanTable>; 8],
+    restart_interval: u16,
+) -> Option<Vec<ScanToken>> {
+    let (max_h, max_v) = frame.max_sampling();
+    // Interleaved MCUs cover every component's sampling block; a single
+    // component is coded block by block over its own (subsampled) size
+    let (mcus, blocks): (usize, Vec<usize>) = if let [(index, ..)] = scan.components[..] {
+        let (_, h, v) = frame.components[index];
+        let columns = (frame.width * h).div_ceil(max_h).div_ceil(8);
+        let rows = (frame.height * v).div_ceil(max_v).div_ceil(8);
+        (columns * rows, vec![0])
+    } else {
+        let columns = frame.width.div_ceil(8 * max_h);
+        let rows = frame.height.div_ceil(8 * max_v);
+        let blocks = scan.components.iter()
+            .enumerate()
+            .flat_map(|(i, &(index, ..))| {
+                let (_, h, v) = frame.components[index];
+                std::iter::repeat_n(i, h * v)
+            })
+            .collect();
+        (columns * rows, blocks)
+    };
+
+    let mut tokens = Vec::new();
+    for mcu in 0..mcus {
+        if restart_interval > 0 && mcu > 0 && mcu % usize::from(restart_interval) == 0 {
+            // Padding bits are dropped and the RSTn marker is rewritten in sequence
+            reader.bits = 0;
+            let marker = reader.data.get(reader.pos..reader.pos + 2)?;
+            if marker[0] != 0xFF || !(0xD0..=0xD7).contains(&marker[1]) {
+                return None;
+            }
+            reader.pos += 2;
+            tokens.push(ScanToken::Restart);
+        }
+
+        for &component in &blocks {
+            let (_, dc, ac) = scan.components[component];
+            let (dc_table, ac_table) = (tables[dc].as_ref()?, tables[4 + ac].as_ref()?);
+
+            let size = dc_table.decode(reader)?;
+            if size > 15 {
+                return None;
+            }
+            tokens.push(ScanToken::Symbol { table: dc as u8, symbol: size, extra: reader.bits(size)?, extra_len: size });
+
+            let mut k = 1;
+            while k < 64 {
+                let symbol = ac_table.decode(reader)?;
+                let (run, size) = (usize::from(symbol >> 4), symbol & 0x0F);
+                tokens.push(ScanToken::Symbol { table: 4 + ac as u8, symbol, extra: reader.bits(size)?, extra_len: size });
+                match (run, size) {
+                    (0, 0) => break,
+                    (15, 0) => k += 16,
+                    (_, 0) => return None,
+                    _ => k += run + 1,
+                }
+            }
+            if k > 64 {
+                return None;
+            }
+        }
+    }
+    reader.bits = 0;
+    Some(tokens)
+}
+
+/// Writes a DHT segment with optimal tables for every table the scan uses
+/// and returns the (code, length) of each symbol per table.
+pub(crate) fn write_optimized_tables(out: &mut Vec<u8>, scan: &JpegScan, tokens: &[ScanToken]) -> Vec<[(u16, u8); 256]> {
+    let mut frequencies = vec![[0u32; 256]; 8];
+    for token in tokens {
+        if let ScanToken::Symbol { table, symbol, .. } = token {
+            frequencies[usize::from(*table)][usize::from(*symbol)] += 1;
+        }
+    }
+
+    let mut used: Vec<usize> = scan.components.iter().flat_map(|&(_, dc, ac)| [dc, 4 + ac]).collect();
+    used.sort_unstable();
+    used.dedup();
+
+    let mut segment = Vec::new();
+    let mut codes = vec![[(0u16, 0u8); 256]; 8];
+    for table in used {
+        let (counts, values) = optimal_huffman_table(&frequencies[table]);
+        segment.push((((table / 4) << 4) | (table % 4)) as u8);
+        segment.extend_from_slice(&counts[1..]);
+        segment.extend_from_slice(&values);
+
+        let mut code = 0u16;
+        let mut values = values.iter();
+        for len in 1..=16u8 {
+            for &value in values.by_ref().take(usize::from(counts[usize::from(len)])) {
+                codes[table][usize::from(value)] = (code, len);
+                code += 1;
+            }
+            code <<= 1;
+        }
+    }
+
+    out.extend_from_slice(&[0xFF, 0xC4]);
+    out.extend_from_slice(&((segment.len() + 2) as u16).to_be_bytes());
+    out.extend_from_slice(&segment);
+    codes
+}
+
+/// Code lengths limited to 16 bits, as counts per length plus the symbols
+/// in code order (ITU T.81 Annex K.2).
+pub(crate) fn optimal_huffman_table(frequencies: &[u32; 256]) -> ([u8; 17], Vec<u8>) {
+    // Symbol 256 reserves the all-ones code, which JPEG forbids
+    let mut freq: Vec<u64> = frequencies.iter().map(|&f| u64::from(f)).collect();
+    freq.push(1);
+    if freq[..256].iter().all(|&f| f == 0) {
+        freq[0] = 1;
+    }
+    let mut code_size = [0usize; 257];
+    let mut others: [Option<usize>; 257] = [None; 257];
+
+    loop {
+        // The two least frequent trees; ties go to the higher symbol
+        let least = |skip: Option<usize>| {
+            (0..257)
+                .filter(|&i| freq[i] > 0 && Some(i) != skip)
+                .min_by(|&a, &b| freq[a].cmp(&freq[b]).then(b.cmp(&a)))
+        };
+        let Some(c1) = least(None) else { break };
+        let Some(c2) = least(Some(c1)) else { break };
+
+        freq[c1] += freq[c2];
+        freq[c2] = 0;
+        for start in [c1, c2] {
+            let mut node = start;
+            code_size[node] += 1;
+            while let Some(next) = others[node] {
+                node = next;
+                code_size[node] += 1;
+            }
+            if start == c1 {
+                others[node] = Some(c2);
+            }
+        }
+    }
+
+    let mut bits = [0usize; 258];
+    for &size in &code_size {
+        if size > 0 {
+            bits[size] += 1;
+        }
+    }
+    // Fold codes longer than 16 bits into shorter ones
+    for i in (17..bits.len()).rev() {
+        while bits[i] > 0 {
+            let mut j = i - 2;
+            while bits[j] == 0 {
+                j -= 1;
+            }
+            bits[i] -= 2;
+            bits[i - 1] += 1;
+            bits[j + 1] += 2;
+            bits[j] -= 1;
+        }
+    }
+    let longest = (1..=16).rev().find(|&i| bits[i] > 0).unwrap_or(1);
+    bits[longest] -= 1;
+
+    let mut counts = [0u8; 17];
+    for (count, &b) in counts.iter_mut().zip(&bits).skip(1) {
+        *count = b as u8;
+    }
+    let max_size = code_size.iter().copied().max().unwrap_or(0);
+    let values = (1..=max_size)
+        .flat_map(|size| (0..256).filter(move |&s| code_size[s] == size).map(|s| s as u8))
+        .collect();
+    (counts, values)
+}
+
+pub(crate) fn encode_scan(out: &mut Vec<u8>, tokens: &[ScanToken], codes: &[[(u16, u8); 256]]) {
+    let mut writer = BitWriter { out, acc: 0, bits: 0 };
+    let mut restarts = 0u8;
+    for token in tokens {
+        match token {
+            ScanToken::Symbol { table, symbol, extra, extra_len } => {
+                let (code, len) = codes[usize::from(*table)][usize::from(*symbol)];
+                writer.put(u32::from(code), len);
+                writer.put(u32::from(*extra), *extra_len);
+            }
+            ScanToken::Restart => {
+                writer.pad();
+                writer.out.extend_from_slice(&[0xFF, 0xD0 + restarts % 8]);
+                restarts = restarts.wrapping_add(1);
+            }
+        }
+    }
+    writer.pad();
+}
+
+pub(crate) struct BitWriter<'a> {
+    pub(crate) out: &'a mut Vec<u8>,
+    pub(crate) acc: u32,
+    pub(crate) bits: u8,
+}
+
+impl BitWriter<'_> {
+    pub(crate) fn put(&mut self, value: u32, len: u8) {
+        self.acc = (self.acc << len) | (value & ((1 << len) - 1));
+        self.bits += len;
+        while self.bits >= 8 {
+            self.bits -= 8;
+            let byte = (self.acc >> self.bits) as u8;
+            self.out.push(byte);
+            if byte == 0xFF {
+                self.out.push(0x00);
+            }
+        }
+        self.acc &= (1 << self.bits) - 1;
+    }
+
+    /// Fills the last byte with 1 bits, as the standard requires.
+    pub(crate) fn pad(&mut self) {
+        self.put(0x7F, (8 - self.bits) % 8);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::OutputFormat;
+    use crate::codec::compress_with_smart_settings;
+    use crate::test_util::{encoder_args, noisy_rgb};
+    use std::path::Path;
+
+    fn assert_same_pixels(original: &[u8], optimized: &[u8]) {
+        assert!(optimized.len() < original.len(), "{} bytes became {}", original.len(), optimized.len());
+        let original = image::load_from_memory(original).unwrap();
+        let optimized = image::load_from_memory(optimized).unwrap();
+        assert_eq!(original, optimized);
+    }
+
+    #[test]
+    fn jpeg_optimize_keeps_pixels() {
+        let plain = encoder_args(&[]);
+        let optimized = encoder_args(&["--jpeg-optimize"]);
+        // Odd sizes leave partial blocks on the right and bottom edges
+        for (width, height) in [(64, 48), (333, 217), (17, 1), (1, 9)] {
+            let img = noisy_rgb(width, height);
+            let path = Path::new("test.png");
+            let before = compress_with_smart_settings(&img, &OutputFormat::Jpeg, 75.0, path, &plain).unwrap();
+            let after = compress_with_smart_settings(&img, &OutputFormat::Jpeg, 75.0, path, &optimized).unwrap();
+            assert_same_pixels(&before, &after);
+        }
+    }
+
+    #[test]
+    fn jpeg_optimize_keeps_grayscale_pixels() {
+        use image::ImageEncoder;
+
+        let gray = image::GrayImage::from_fn(101, 77, |x, y| image::Luma([((x * 7) ^ (y * 5)) as u8]));
+        let mut data = Vec::new();
+        image::codecs::jpeg::JpegEncoder::new_with_quality(&mut data, 80)
+            .write_image(gray.as_raw(), gray.width(), gray.height(), image::ExtendedColorType::L8)
+            .unwrap();
+        assert_same_pixels(&data, &optimize_jpeg_huffman(&data).unwrap());
+    }
+
+    #[test]
+    fn jpeg_optimize_keeps_subsampled_restart_pixels() {
+        // 37x29, 4:2:0 chroma and a restart marker every 2 MCUs
+        let data = include_bytes!("../../tests/fixtures/subsampled_restart.jpg");
+        assert_same_pixels(data, &optimize_jpeg_huffman(data).unwrap());
+    }
+}
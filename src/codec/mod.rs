@@ -0,0 +1,337 @@
+//! Encoders for every output format.
+
+mod jpeg_huffman;
+mod palette;
+mod png_interlace;
+
+use anyhow::{Context, Result};
+use std::{cell::RefCell, path::Path};
+
+use crate::cli::{ConcreteFormat, EncoderArgs, OutputFormat, WebpAlpha};
+use jpeg_huffman::optimize_jpeg_huffman;
+use palette::{compress_gif, compress_png_palette, exact_palette, has_few_colors, posterize, reduce_color_type, write_palette_png};
+use png_interlace::compress_png_interlaced;
+pub(crate) use png_interlace::push_png_chunk;
+
+/// Encodes `img`, resolving `best` to whichever concrete format came out
+/// smallest and `auto` to the one its content suits.
+pub(crate) fn encode_resolved(
+    img: &image::DynamicImage,
+    format: &OutputFormat,
+    quality: Option<f32>,
+    input_path: &Path,
+    encoder: &EncoderArgs,
+) -> Result<(ConcreteFormat, Vec<u8>)> {
+    let quality_of = |format: &OutputFormat| quality.unwrap_or_else(|| format.default_quality());
+    if let Some(concrete) = format.concrete() {
+        let data = compress_with_smart_settings(img, format, quality_of(format), input_path, encoder)?;
+        return Ok((concrete, data));
+    }
+    if matches!(format, OutputFormat::Auto) {
+        let chosen = if has_few_colors(img) { ConcreteFormat::Png } else { ConcreteFormat::Webp };
+        let format = chosen.into();
+        let data = compress_with_smart_settings(img, &format, quality_of(&format), input_path, encoder)?;
+        return Ok((chosen, data));
+    }
+
+    let encode = |candidate: ConcreteFormat| {
+        let format = candidate.into();
+        compress_with_smart_settings(img, &format, quality_of(&format), input_path, encoder)
+            .map(|data| (candidate, data))
+    };
+
+    // Nested joins run on the same global pool as the file-level par_iter,
+    // so idle workers steal these encodes instead of spawning extra threads
+    let ((jpeg, png), webp) = rayon::join(
+        || rayon::join(|| (!encoder.lossless).then(|| encode(ConcreteFormat::Jpeg)), || encode(ConcreteFormat::Png)),
+        || encode(ConcreteFormat::Webp),
+    );
+
+    let mut first_error = None;
+    let mut best: Option<(ConcreteFormat, Vec<u8>)> = None;
+    for result in [jpeg, Some(png), Some(webp)].into_iter().flatten() {
+        match result {
+            Ok(candidate) => {
+                if best.as_ref().is_none_or(|(_, data)| candidate.1.len() < data.len()) {
+                    best = Some(candidate);
+                }
+            }
+            Err(e) => {
+                first_error.get_or_insert(e);
+            }
+        }
+    }
+
+    best.ok_or_else(|| first_error.unwrap_or_else(|| anyhow::anyhow!("No format could be encoded")))
+}
+
+pub(crate) fn compress_with_smart_settings(
+    img: &image::DynamicImage, 
+    format: &OutputFormat, 
+    quality: f32,
+    input_path: &Path,
+    encoder: &EncoderArgs,
+) -> Result<Vec<u8>> {
+    let input_ext = input_path.extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| ext.to_lowercase())
+        .unwrap_or_default();
+
+    match format {
+        OutputFormat::Jpeg => {
+            // For JPEG output, always compress with specified quality
+            let data = compress_jpeg(img, quality)?;
+            if !encoder.jpeg_optimize {
+                return Ok(data);
+            }
+            Ok(optimize_jpeg_huffman(&data).filter(|optimized| optimized.len() < data.len()).unwrap_or(data))
+        },
+        OutputFormat::Png => {
+            let posterized = encoder.png_bit_depth.map(|bits| posterize(img, bits, encoder));
+            let img = posterized.as_ref().unwrap_or(img);
+            if let Some(colors) = encoder.png_colors {
+                return compress_png_palette(img, colors, encoder);
+            }
+            // PNG compression - avoid converting JPEG to PNG unless necessary
+            if input_ext == "jpg" || input_ext == "jpeg" {
+                // Converting JPEG to PNG usually increases size, use higher compression
+                compress_png_aggressive(img, encoder)
+            } else {
+                compress_png(img, encoder)
+            }
+        },
+        OutputFormat::Webp => {
+            // WebP is generally efficient for all input types
+            compress_webp(img, quality, encoder)
+        },
+        OutputFormat::Ico => compress_ico(img, &encoder.ico_sizes),
+        OutputFormat::Bmp | OutputFormat::Tga => write_uncompressed(img, format),
+        OutputFormat::Gif => compress_gif(img, encoder),
+        OutputFormat::Best | OutputFormat::Auto => {
+            encode_resolved(img, format, Some(quality), input_path, encoder).map(|(_, data)| data)
+        }
+    }
+}
+
+/// Encodes `img` as a baseline JPEG, dropping any alpha. Conversion and
+/// encoding go through per-thread buffers reused across calls.
+pub fn compress_jpeg(img: &image::DynamicImage, quality: f32) -> Result<Vec<u8>> {
+    use image::codecs::jpeg::JpegEncoder;
+    use image::ImageEncoder;
+
+    // The JPEG encoder only takes whole qualities
+    let quality = quality.round().clamp(1.0, 100.0) as u8;
+
+    // Convert to RGB to strip alpha channel and metadata
+    let (width, height) = (img.width(), img.height());
+    with_rgb8(img, |rgb| {
+        encode_into_scratch(|buffer| {
+            JpegEncoder::new_with_quality(buffer, quality)
+                .write_image(rgb, width, height, image::ExtendedColorType::Rgb8)
+                .with_context(|| "Failed to encode JPEG")
+        })
+    })
+}
+
+// Per-worker scratch reused from file to file, so a batch of thousands of
+// small images doesn't allocate and regrow the same buffers every time
+thread_local! {
+    static RGB_SCRATCH: RefCell<Vec<u8>> = const { RefCell::new(Vec::new()) };
+    static ENCODE_SCRATCH: RefCell<Vec<u8>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Scratch grown past this by one huge image is released instead of kept.
+pub(crate) const MAX_RETAINED_SCRATCH: usize = 64 << 20;
+
+/// Calls `f` with the image's pixels as packed 8-bit RGB. RGB8 images are
+/// passed as they are; anything else is converted into the thread's scratch.
+pub(crate) fn with_rgb8<T>(img: &image::DynamicImage, f: impl FnOnce(&[u8]) -> T) -> T {
+    use image::DynamicImage;
+
+    if let DynamicImage::ImageRgb8(rgb) = img {
+        return f(rgb.as_raw());
+    }
+
+    RGB_SCRATCH.with_borrow_mut(|scratch| {
+        scratch.clear();
+        match img {
+            DynamicImage::ImageRgba8(rgba) => {
+                scratch.extend(rgba.as_raw().chunks_exact(4).flat_map(|p| [p[0], p[1], p[2]]));
+            }
+            DynamicImage::ImageLuma8(luma) => scratch.extend(luma.as_raw().iter().flat_map(|&l| [l, l, l])),
+            DynamicImage::ImageLumaA8(luma_alpha) => {
+                scratch.extend(luma_alpha.as_raw().chunks_exact(2).flat_map(|p| [p[0], p[0], p[0]]));
+            }
+            _ => scratch.extend_from_slice(img.to_rgb8().as_raw()),
+        }
+        let result = f(scratch);
+        if scratch.capacity() > MAX_RETAINED_SCRATCH {
+            *scratch = Vec::new();
+        }
+        result
+    })
+}
+
+/// Runs an encoder against the thread's scratch buffer and returns an
+/// exactly sized copy of what it wrote.
+pub(crate) fn encode_into_scratch(encode: impl FnOnce(&mut Vec<u8>) -> Result<()>) -> Result<Vec<u8>> {
+    ENCODE_SCRATCH.with_borrow_mut(|scratch| {
+        scratch.clear();
+        let result = encode(scratch).map(|()| scratch.as_slice().to_vec());
+        if scratch.capacity() > MAX_RETAINED_SCRATCH {
+            *scratch = Vec::new();
+        }
+        result
+    })
+}
+
+pub(crate) fn compress_png(img: &image::DynamicImage, encoder: &EncoderArgs) -> Result<Vec<u8>> {
+    use image::codecs::png::PngEncoder;
+
+    // Indexed sources decode to RGB(A); give them their palette back
+    if let Some(exact) = exact_palette(img) {
+        return write_palette_png(&exact, encoder);
+    }
+    let reduced = reduce_color_type(img);
+    let img = reduced.as_ref().unwrap_or(img);
+
+    if encoder.png_interlace {
+        return compress_png_interlaced(img, encoder);
+    }
+    
+    // Defaults to best compression with adaptive filtering
+    encode_into_scratch(|buffer| {
+        let encoder = PngEncoder::new_with_quality(
+            buffer,
+            encoder.png_compression.image_type(),
+            encoder.png_filter.image_type(),
+        );
+        img.write_with_encoder(encoder).with_context(|| "Failed to encode PNG")
+    })
+}
+
+pub(crate) fn compress_png_aggressive(img: &image::DynamicImage, encoder: &EncoderArgs) -> Result<Vec<u8>> {
+    use image::codecs::png::PngEncoder;
+    
+    // JPEG carries no alpha; keep grayscale scans single-channel
+    let reduced = reduce_color_type(img);
+    let img = reduced.as_ref().unwrap_or(img);
+    let rgb_img = if img.color().has_color() {
+        image::DynamicImage::ImageRgb8(img.to_rgb8())
+    } else {
+        image::DynamicImage::ImageLuma8(img.to_luma8())
+    };
+
+    if encoder.png_interlace {
+        return compress_png_interlaced(&rgb_img, encoder);
+    }
+
+    encode_into_scratch(|buffer| {
+        let encoder = PngEncoder::new_with_quality(
+            buffer,
+            encoder.png_compression.image_type(),
+            encoder.png_filter.image_type(),
+        );
+        rgb_img.write_with_encoder(encoder).with_context(|| "Failed to encode PNG")
+    })
+}
+
+pub(crate) fn compress_webp(img: &image::DynamicImage, quality: f32, encoder: &EncoderArgs) -> Result<Vec<u8>> {
+    let lossless = encoder.lossless || quality >= 100.0;
+    if img.color().has_alpha() {
+        let rgba_img = img.to_rgba8();
+        let transparent = rgba_img.pixels().any(|pixel| pixel[3] < 255);
+        if encoder.lossless || transparent {
+            let exact = lossless || match encoder.webp_alpha {
+                WebpAlpha::Lossless => true,
+                WebpAlpha::Lossy => false,
+                WebpAlpha::Auto => has_few_colors(img),
+            };
+            return compress_webp_alpha(&rgba_img, quality, exact, encoder.webp_alpha_quality);
+        }
+    }
+
+    // Convert to RGB8 to strip metadata and ensure compatibility
+    let (width, height) = (img.width(), img.height());
+    let webp_data = with_rgb8(img, |rgb| {
+        // Use direct encoding for maximum speed; quality 100 has always meant lossless
+        let encoder = webp::Encoder::from_rgb(rgb, width, height);
+        if lossless {
+            encoder.encode_lossless().to_vec()
+        } else {
+            encoder.encode(quality).to_vec()
+        }
+    });
+
+    Ok(webp_data)
+}
+
+/// Encode keeping the alpha channel, either pixel-exact or as lossy color with
+/// its own alpha plane quality.
+pub(crate) fn compress_webp_alpha(rgba_img: &image::RgbaImage, quality: f32, lossless: bool, alpha_quality: u8) -> Result<Vec<u8>> {
+    let (width, height) = rgba_img.dimensions();
+    let mut config = webp::WebPConfig::new()
+        .map_err(|_| anyhow::anyhow!("Failed to initialise the WebP encoder"))?;
+    if lossless {
+        // Keep the color under fully transparent pixels too, which libwebp
+        // would otherwise rewrite to help compression
+        config.lossless = 1;
+        config.alpha_compression = 0;
+        config.exact = 1;
+    } else {
+        config.quality = quality;
+        config.alpha_compression = 1;
+        config.alpha_quality = i32::from(alpha_quality);
+    }
+    webp::Encoder::from_rgba(rgba_img, width, height)
+        .encode_advanced(&config)
+        .map(|data| data.to_vec())
+        .map_err(|e| anyhow::anyhow!("Failed to encode WebP with alpha: {:?}", e))
+}
+
+pub(crate) fn compress_ico(img: &image::DynamicImage, sizes: &[u32]) -> Result<Vec<u8>> {
+    use image::codecs::ico::{IcoEncoder, IcoFrame};
+    use image::imageops::{self, FilterType};
+
+    let mut frames = Vec::with_capacity(sizes.len());
+    for &size in sizes {
+        // Fit inside the square and center on a transparent canvas
+        let scaled = img.resize(size, size, FilterType::Lanczos3).to_rgba8();
+        let mut canvas = image::RgbaImage::new(size, size);
+        let x = i64::from((size - scaled.width()) / 2);
+        let y = i64::from((size - scaled.height()) / 2);
+        imageops::overlay(&mut canvas, &scaled, x, y);
+
+        let frame = IcoFrame::as_png(canvas.as_raw(), size, size, image::ExtendedColorType::Rgba8)
+            .with_context(|| format!("Failed to encode {}x{} icon frame", size, size))?;
+        frames.push(frame);
+    }
+
+    let mut buffer = Vec::new();
+    IcoEncoder::new(&mut buffer)
+        .encode_images(&frames)
+        .with_context(|| "Failed to encode ICO")?;
+
+    Ok(buffer)
+}
+
+pub(crate) fn write_uncompressed(img: &image::DynamicImage, format: &OutputFormat) -> Result<Vec<u8>> {
+    use image::codecs::{bmp::BmpEncoder, tga::TgaEncoder};
+
+    // Normalise to 8-bit since neither encoder accepts 16-bit or float input
+    let img = if img.color().has_alpha() {
+        image::DynamicImage::ImageRgba8(img.to_rgba8())
+    } else {
+        image::DynamicImage::ImageRgb8(img.to_rgb8())
+    };
+
+    let mut buffer = Vec::new();
+    match format {
+        OutputFormat::Bmp => img.write_with_encoder(BmpEncoder::new(&mut buffer))
+            .with_context(|| "Failed to encode BMP")?,
+        _ => img.write_with_encoder(TgaEncoder::new(&mut buffer))
+            .with_context(|| "Failed to encode TGA")?,
+    }
+
+    Ok(buffer)
+}
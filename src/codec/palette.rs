@@ -0,0 +1,418 @@
+//! Palette PNGs and GIFs: exact palettes, quantization and dithering.
+
+use anyhow::{Context, Result};
+use std::collections::{HashMap, HashSet};
+
+use crate::cli::{Dither, EncoderArgs};
+use super::png_interlace::{write_interlaced_png, PngHeader};
+
+/// Whether `img` looks like a graphic rather than a photo: at most 256
+/// distinct colors among up to 64K pixels sampled on an even grid across it,
+/// read in place rather than from an RGBA copy of the whole image.
+pub(crate) fn has_few_colors(img: &image::DynamicImage) -> bool {
+    use image::GenericImageView;
+
+    const SAMPLES: u64 = 1 << 16;
+
+    let (width, height) = img.dimensions();
+    let pixels = u64::from(width) * u64::from(height);
+    // The same stride on both axes keeps the grid square
+    let step = (pixels.div_ceil(SAMPLES) as f64).sqrt().ceil().max(1.0) as usize;
+    let mut seen = HashSet::new();
+    for y in (0..height).step_by(step) {
+        for x in (0..width).step_by(step) {
+            seen.insert(img.get_pixel(x, y).0);
+            if seen.len() > 256 {
+                return false;
+            }
+        }
+    }
+    true
+}
+
+/// The narrowest PNG color type that holds `img` without loss: alpha is
+/// dropped when every pixel is opaque, color when every pixel is gray.
+/// `None` when the decoded color type is already the narrowest.
+pub(crate) fn reduce_color_type(img: &image::DynamicImage) -> Option<image::DynamicImage> {
+    use image::{DynamicImage, GenericImageView};
+
+    let color = img.color();
+    let wide = color.bytes_per_pixel() / color.channel_count() == 2;
+    let (mut gray, mut opaque) = (!color.has_color(), !color.has_alpha());
+    if !gray || !opaque {
+        // 8-bit samples widen exactly (x * 257), so one check covers both depths
+        let check = |[r, g, b, a]: [u16; 4]| (r == g && g == b, a == u16::MAX);
+        let (all_gray, all_opaque) = match img {
+            DynamicImage::ImageRgba16(_) | DynamicImage::ImageRgb16(_) | DynamicImage::ImageLumaA16(_) => img.to_rgba16()
+                .pixels()
+                .fold((true, true), |(g, o), p| {
+                    let (pg, po) = check(p.0);
+                    (g && pg, o && po)
+                }),
+            _ => img.pixels().fold((true, true), |(g, o), (_, _, p)| {
+                let (pg, po) = check(p.0.map(|c| u16::from(c) * 257));
+                (g && pg, o && po)
+            }),
+        };
+        gray |= all_gray;
+        opaque |= all_opaque;
+    }
+
+    let reduced = match (gray, opaque, wide) {
+        (true, true, false) => DynamicImage::ImageLuma8(img.to_luma8()),
+        (true, true, true) => DynamicImage::ImageLuma16(img.to_luma16()),
+        (true, false, false) => DynamicImage::ImageLumaA8(img.to_luma_alpha8()),
+        (true, false, true) => DynamicImage::ImageLumaA16(img.to_luma_alpha16()),
+        (false, true, false) => DynamicImage::ImageRgb8(img.to_rgb8()),
+        (false, true, true) => DynamicImage::ImageRgb16(img.to_rgb16()),
+        (false, false, _) => return None,
+    };
+    (reduced.color() != color).then_some(reduced)
+}
+
+/// A lossless palette for 8-bit images with at most 256 distinct colors.
+/// Grayscale images only take one when it packs below 8 bits per pixel,
+/// since plain 8-bit gray compresses as well otherwise.
+pub(crate) fn exact_palette(img: &image::DynamicImage) -> Option<Quantized> {
+    let color = img.color();
+    if color.bytes_per_pixel() != color.channel_count() {
+        return None;
+    }
+
+    let rgba = img.to_rgba8();
+    let mut colors: HashMap<[u8; 4], u8> = HashMap::new();
+    for pixel in rgba.pixels() {
+        if colors.len() == 256 && !colors.contains_key(&pixel.0) {
+            return None;
+        }
+        colors.entry(pixel.0).or_insert(0);
+    }
+
+    let opaque_gray = colors.keys().all(|[r, g, b, a]| r == g && g == b && *a == 255);
+    if opaque_gray && colors.len() > 16 {
+        return None;
+    }
+
+    // Translucent entries first keeps the tRNS chunk as short as possible
+    let mut palette: Vec<[u8; 4]> = colors.keys().copied().collect();
+    palette.sort_unstable_by_key(|c| (c[3] == 255, *c));
+    for (index, entry) in palette.iter().enumerate() {
+        colors.insert(*entry, index as u8);
+    }
+
+    let (width, height) = rgba.dimensions();
+    let indices = rgba.pixels().map(|pixel| colors[&pixel.0]).collect();
+    Some(Quantized { width, height, palette, indices })
+}
+
+/// An image reduced to indices into a palette of at most 256 RGBA colors.
+pub(crate) struct Quantized {
+    pub(crate) width: u32,
+    pub(crate) height: u32,
+    pub(crate) palette: Vec<[u8; 4]>,
+    pub(crate) indices: Vec<u8>,
+}
+
+/// Builds an N-color palette with NeuQuant and maps every pixel onto it,
+/// diffusing the quantization error according to `--dither`.
+pub(crate) fn quantize(img: &image::DynamicImage, colors: u16, encoder: &EncoderArgs) -> Quantized {
+    let rgba = img.to_rgba8();
+    let (width, height) = rgba.dimensions();
+    let nq = color_quant::NeuQuant::new(10, usize::from(colors), rgba.as_raw());
+    // NeuQuant can drift opaque entries to 254; keep opaque sources free of tRNS
+    let opaque = !img.color().has_alpha();
+    let palette: Vec<[u8; 4]> = nq.color_map_rgba()
+        .chunks_exact(4)
+        .map(|c| [c[0], c[1], c[2], if opaque { 255 } else { c[3] }])
+        .collect();
+
+    let level = match encoder.dither {
+        Dither::None => 0.0,
+        Dither::FloydSteinberg => encoder.dither_level,
+    };
+
+    let row_len = width as usize;
+    let mut indices = Vec::with_capacity(row_len * height as usize);
+    // Error carried into the current and next row, one RGBA entry per pixel
+    let mut current = vec![[0.0f32; 4]; row_len + 2];
+    let mut next = vec![[0.0f32; 4]; row_len + 2];
+
+    for row in rgba.rows() {
+        for (x, pixel) in row.enumerate() {
+            let mut wanted = [0u8; 4];
+            let mut exact = [0.0f32; 4];
+            for c in 0..4 {
+                exact[c] = (f32::from(pixel[c]) + current[x + 1][c]).clamp(0.0, 255.0);
+                wanted[c] = exact[c].round() as u8;
+            }
+
+            let index = nq.index_of(&wanted);
+            indices.push(index as u8);
+
+            if level > 0.0 {
+                let chosen = palette[index];
+                for c in 0..4 {
+                    let error = (exact[c] - f32::from(chosen[c])) * level;
+                    current[x + 2][c] += error * 7.0 / 16.0;
+                    next[x][c] += error * 3.0 / 16.0;
+                    next[x + 1][c] += error * 5.0 / 16.0;
+                    next[x + 2][c] += error / 16.0;
+                }
+            }
+        }
+        std::mem::swap(&mut current, &mut next);
+        next.iter_mut().for_each(|e| *e = [0.0; 4]);
+    }
+
+    Quantized { width, height, palette, indices }
+}
+
+/// Rounds every color channel to `bits` bits (spread back over 0-255), with
+/// the rounding error diffused like [`quantize`]. Alpha is kept as is.
+pub(crate) fn posterize(img: &image::DynamicImage, bits: u8, encoder: &EncoderArgs) -> image::DynamicImage {
+    let levels = f32::from((1u16 << bits) - 1);
+    let level = match encoder.dither {
+        Dither::None => 0.0,
+        Dither::FloydSteinberg => encoder.dither_level,
+    };
+
+    let mut rgba = img.to_rgba8();
+    let row_len = rgba.width() as usize;
+    // Error carried into the current and next row, one RGB entry per pixel
+    let mut current = vec![[0.0f32; 3]; row_len + 2];
+    let mut next = vec![[0.0f32; 3]; row_len + 2];
+
+    for row in rgba.rows_mut() {
+        for (x, pixel) in row.enumerate() {
+            for c in 0..3 {
+                let exact = (f32::from(pixel[c]) + current[x + 1][c]).clamp(0.0, 255.0);
+                let chosen = ((exact * levels / 255.0).round() * 255.0 / levels).round();
+                pixel[c] = chosen as u8;
+
+                let error = (exact - chosen) * level;
+                current[x + 2][c] += error * 7.0 / 16.0;
+                next[x][c] += error * 3.0 / 16.0;
+                next[x + 1][c] += error * 5.0 / 16.0;
+                next[x + 2][c] += error / 16.0;
+            }
+        }
+        std::mem::swap(&mut current, &mut next);
+        next.iter_mut().for_each(|e| *e = [0.0; 3]);
+    }
+
+    if img.color().has_alpha() {
+        image::DynamicImage::ImageRgba8(rgba)
+    } else {
+        image::DynamicImage::ImageRgb8(image::DynamicImage::ImageRgba8(rgba).to_rgb8())
+    }
+}
+
+pub(crate) fn compress_png_palette(img: &image::DynamicImage, colors: u16, encoder: &EncoderArgs) -> Result<Vec<u8>> {
+    write_palette_png(&quantize(img, colors, encoder), encoder)
+}
+
+pub(crate) fn write_palette_png(quantized: &Quantized, encoder: &EncoderArgs) -> Result<Vec<u8>> {
+    // Pack small palettes into 1, 2 or 4 bits per pixel
+    let (depth, bits) = match quantized.palette.len() {
+        0..=2 => (png::BitDepth::One, 1),
+        3..=4 => (png::BitDepth::Two, 2),
+        5..=16 => (png::BitDepth::Four, 4),
+        _ => (png::BitDepth::Eight, 8),
+    };
+    let per_byte = 8 / bits;
+    let data: Vec<u8> = quantized.indices
+        .chunks_exact(quantized.width as usize)
+        .flat_map(|row| {
+            row.chunks(per_byte).map(|group| {
+                group.iter().enumerate().fold(0u8, |byte, (i, &index)| byte | index << (8 - bits * (i + 1)))
+            })
+        })
+        .collect();
+
+    let rgb: Vec<u8> = quantized.palette.iter().flat_map(|c| [c[0], c[1], c[2]]).collect();
+    // tRNS may stop at the last translucent entry; the rest default to opaque
+    let opaque_tail = quantized.palette.iter().rev().take_while(|c| c[3] == 255).count();
+    let alpha: Vec<u8> = quantized.palette[..quantized.palette.len() - opaque_tail]
+        .iter()
+        .map(|c| c[3])
+        .collect();
+
+    if encoder.png_interlace {
+        let header = PngHeader { width: quantized.width, height: quantized.height, bit_depth: bits as u8, color_type: 3 };
+        let mut chunks: Vec<(&[u8; 4], Vec<u8>)> = vec![(b"PLTE", rgb)];
+        if !alpha.is_empty() {
+            chunks.push((b"tRNS", alpha));
+        }
+        return Ok(write_interlaced_png(&header, &chunks, &quantized.indices, 1, encoder));
+    }
+
+    let mut buffer = Vec::new();
+    let mut png_encoder = png::Encoder::new(&mut buffer, quantized.width, quantized.height);
+    png_encoder.set_color(png::ColorType::Indexed);
+    png_encoder.set_depth(depth);
+    png_encoder.set_palette(rgb);
+    if !alpha.is_empty() {
+        png_encoder.set_trns(alpha);
+    }
+    png_encoder.set_compression(encoder.png_compression.png_type());
+    png_encoder.set_filter(encoder.png_filter.png_type());
+
+    let mut writer = png_encoder.write_header().context("Failed to encode palette PNG")?;
+    writer.write_image_data(&data).context("Failed to encode palette PNG")?;
+    writer.finish().context("Failed to encode palette PNG")?;
+
+    Ok(buffer)
+}
+
+/// GIF transparency is a single on/off palette entry, so alpha is thresholded
+/// at 50% and every transparent pixel shares one reserved index.
+pub(crate) fn compress_gif(img: &image::DynamicImage, encoder: &EncoderArgs) -> Result<Vec<u8>> {
+    use image::codecs::gif::GifEncoder;
+
+    let alpha = img.to_rgba8();
+    let has_transparency = alpha.pixels().any(|p| p[3] < 128);
+    // The transparent entry counts against --png-colors, which may leave one color
+    let colors = encoder.png_colors.unwrap_or(256) - u16::from(has_transparency);
+    let opaque = image::DynamicImage::ImageRgb8(img.to_rgb8());
+    let quantized = quantize(&opaque, colors, encoder);
+
+    // At most 256 distinct colors, so the GIF encoder keeps this palette as is
+    let frame = image::RgbaImage::from_fn(quantized.width, quantized.height, |x, y| {
+        if alpha.get_pixel(x, y)[3] < 128 {
+            return image::Rgba([0, 0, 0, 0]);
+        }
+        let index = quantized.indices[(y * quantized.width + x) as usize];
+        let [r, g, b, _] = quantized.palette[usize::from(index)];
+        image::Rgba([r, g, b, 255])
+    });
+
+    let mut buffer = Vec::new();
+    GifEncoder::new(&mut buffer)
+        .encode_frame(image::Frame::new(frame))
+        .with_context(|| "Failed to encode GIF")?;
+
+    Ok(buffer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::compress_png;
+    use crate::test_util::encoder_args;
+
+    #[test]
+    fn gif_transparency_counts_against_png_colors() {
+        let img = image::DynamicImage::ImageRgba8(image::RgbaImage::from_fn(32, 32, |x, y| {
+            let alpha = if x < 8 { 0 } else { 255 };
+            image::Rgba([(x * 8) as u8, (y * 8) as u8, ((x ^ y) * 8) as u8, alpha])
+        }));
+        let gif = compress_gif(&img, &encoder_args(&["--png-colors", "2"])).unwrap();
+        let decoded = image::load_from_memory(&gif).unwrap().to_rgba8();
+        let colors: HashSet<[u8; 4]> = decoded.pixels().map(|p| p.0).collect();
+        assert_eq!(colors.len(), 2, "{:?}", colors);
+    }
+
+    /// A 32x32 image of `shade(value, index)`, where `value` runs through
+    /// every byte and `index` numbers the pixels.
+    fn shades<P: image::Pixel>(shade: impl Fn(u8, u32) -> P) -> image::ImageBuffer<P, Vec<P::Subpixel>> {
+        image::ImageBuffer::from_fn(32, 32, |x, y| shade((x * 8) as u8 ^ (y * 7) as u8, x + y * 32))
+    }
+
+    #[test]
+    fn reduce_color_type_finds_narrowest_type() {
+        use image::{ColorType, DynamicImage, Luma, LumaA, Rgb, Rgba};
+
+        let cases = [
+            (DynamicImage::ImageRgb8(shades(|v, _| Rgb([v, v, v]))), Some(ColorType::L8)),
+            (DynamicImage::ImageRgba8(shades(|v, _| Rgba([v, v, v, 255]))), Some(ColorType::L8)),
+            (DynamicImage::ImageRgba8(shades(|v, i| Rgba([v, v, v, i as u8]))), Some(ColorType::La8)),
+            (DynamicImage::ImageLumaA8(shades(|v, _| LumaA([v, 255]))), Some(ColorType::L8)),
+            (DynamicImage::ImageRgba8(shades(|v, i| Rgba([v, i as u8, 9, 255]))), Some(ColorType::Rgb8)),
+            (DynamicImage::ImageRgba16(shades(|v, i| Rgba([u16::from(v) * 3, i as u16, 9, u16::MAX]))), Some(ColorType::Rgb16)),
+            (DynamicImage::ImageRgba16(shades(|v, i| Rgba([u16::from(v) * 3, u16::from(v) * 3, u16::from(v) * 3, i as u16]))), Some(ColorType::La16)),
+            (DynamicImage::ImageRgb16(shades(|v, _| Rgb([u16::from(v) * 3; 3]))), Some(ColorType::L16)),
+            // Already as narrow as it gets
+            (DynamicImage::ImageLuma8(shades(|v, _| Luma([v]))), None),
+            (DynamicImage::ImageRgb8(shades(|v, i| Rgb([v, i as u8, 9]))), None),
+            (DynamicImage::ImageRgba8(shades(|v, i| Rgba([v, i as u8, 9, v]))), None),
+            (DynamicImage::ImageRgba16(shades(|v, i| Rgba([u16::from(v), i as u16, 9, 1]))), None),
+        ];
+        for (img, expected) in cases {
+            let reduced = reduce_color_type(&img);
+            assert_eq!(reduced.as_ref().map(|r| r.color()), expected, "{:?}", img.color());
+            if let Some(reduced) = reduced {
+                assert_eq!(reduced.to_rgba16(), img.to_rgba16(), "{:?}", img.color());
+            }
+        }
+    }
+
+    #[test]
+    fn exact_palette_keeps_up_to_256_colors() {
+        use image::{DynamicImage, Luma, Rgba};
+
+        let img = DynamicImage::ImageRgba8(shades(|v, i| Rgba([v & 0xF0, 0, 255, if i % 3 == 0 { 128 } else { 255 }])));
+        let exact = exact_palette(&img).unwrap();
+        assert!(exact.palette.len() <= 256);
+        // Translucent entries lead, so tRNS stops at the first opaque one
+        let translucent = exact.palette.iter().take_while(|c| c[3] != 255).count();
+        assert!(exact.palette[translucent..].iter().all(|c| c[3] == 255));
+        let decoded: Vec<[u8; 4]> = exact.indices.iter().map(|&i| exact.palette[usize::from(i)]).collect();
+        let pixels: Vec<[u8; 4]> = img.to_rgba8().pixels().map(|p| p.0).collect();
+        assert_eq!(decoded, pixels);
+
+        let many = DynamicImage::ImageRgba8(shades(|v, i| Rgba([v, i as u8, 0, 255])));
+        assert!(exact_palette(&many).is_none());
+        let deep = DynamicImage::ImageRgba16(shades(|v, _| Rgba([u16::from(v & 0xF0), 0, 0, u16::MAX])));
+        assert!(exact_palette(&deep).is_none());
+        // Gray only takes a palette when it packs below 8 bits
+        let few_grays = DynamicImage::ImageLuma8(shades(|v, _| Luma([v & 0xF0])));
+        assert_eq!(exact_palette(&few_grays).unwrap().palette.len(), 16);
+        let more_grays = DynamicImage::ImageLuma8(shades(|v, _| Luma([v & 0xF8])));
+        assert!(exact_palette(&more_grays).is_none());
+    }
+
+    #[test]
+    fn compress_png_writes_narrowest_color_type() {
+        use image::{DynamicImage, Rgba};
+
+        // IHDR color type: 0 gray, 2 RGB, 3 palette, 4 gray + alpha, 6 RGBA
+        let cases = [
+            (shades(|v, _| Rgba([v, v, v, 255])), 0),
+            (shades(|v, i| Rgba([v, v, v, i as u8])), 4),
+            (shades(|v, i| Rgba([v, i as u8, 9, 255])), 2),
+            (shades(|v, i| Rgba([v, i as u8, 9, v])), 6),
+            (shades(|v, _| Rgba([v & 0xF0, 40, 40, 255])), 3),
+            (shades(|v, _| Rgba([v & 0xF0, v & 0xF0, v & 0xF0, 255])), 3),
+        ];
+        for (rgba, color_type) in cases {
+            let img = DynamicImage::ImageRgba8(rgba);
+            let png = compress_png(&img, &encoder_args(&[])).unwrap();
+            assert_eq!(png[25], color_type, "{:?}", &img.to_rgba8().as_raw()[..8]);
+            assert_eq!(image::load_from_memory(&png).unwrap().to_rgba8(), img.to_rgba8());
+        }
+    }
+
+    #[test]
+    fn quantize_stays_within_png_colors() {
+        use crate::test_util::noisy_rgb;
+        use image::{DynamicImage, Rgba};
+
+        let translucent = DynamicImage::ImageRgba8(shades(|v, i| Rgba([v, i as u8, v ^ 0x55, (i / 4) as u8])));
+        let images = [noisy_rgb(64, 48), translucent, noisy_rgb(1, 1), noisy_rgb(3, 2)];
+        for dither in ["none", "floyd-steinberg"] {
+            for colors in [2, 3, 16, 17, 255, 256] {
+                let encoder = encoder_args(&["--png-colors", &colors.to_string(), "--dither", dither]);
+                for img in &images {
+                    let quantized = quantize(img, colors, &encoder);
+                    assert!(quantized.palette.len() <= usize::from(colors), "{} entries for {colors}", quantized.palette.len());
+                    assert!(quantized.indices.iter().all(|&i| usize::from(i) < quantized.palette.len()));
+
+                    let png = compress_png_palette(img, colors, &encoder).unwrap();
+                    let decoded = image::load_from_memory(&png).unwrap().to_rgba8();
+                    let distinct: HashSet<[u8; 4]> = decoded.pixels().map(|p| p.0).collect();
+                    assert!(distinct.len() <= usize::from(colors), "{} colors for {colors}", distinct.len());
+                }
+            }
+        }
+    }
+}
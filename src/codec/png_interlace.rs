@@ -0,0 +1,231 @@
+//! Adam7-interlaced PNG encoding.
+
+use anyhow::Result;
+
+use crate::cli::{EncoderArgs, PngFilter};
+use crate::metadata::PNG_SIGNATURE;
+
+/// The IHDR fields an interlaced PNG is written with.
+pub(crate) struct PngHeader {
+    pub(crate) width: u32,
+    pub(crate) height: u32,
+    pub(crate) bit_depth: u8,
+    pub(crate) color_type: u8,
+}
+
+/// Pass origins and steps of the Adam7 scheme: (x, y, dx, dy).
+pub(crate) const ADAM7_PASSES: [(usize, usize, usize, usize); 7] = [
+    (0, 0, 8, 8),
+    (4, 0, 8, 8),
+    (0, 4, 4, 8),
+    (2, 0, 4, 4),
+    (0, 2, 2, 4),
+    (1, 0, 2, 2),
+    (0, 1, 1, 2),
+];
+
+/// The `png` crate and `image` only write progressive-less PNGs, so the
+/// interlaced variant is assembled here from 8- or 16-bit samples.
+pub(crate) fn compress_png_interlaced(img: &image::DynamicImage, encoder: &EncoderArgs) -> Result<Vec<u8>> {
+    use image::ColorType;
+
+    let (width, height) = (img.width(), img.height());
+    let wide = img.color().bytes_per_pixel() / img.color().channel_count() == 2;
+    let to_be_bytes = |samples: Vec<u16>| samples.into_iter().flat_map(u16::to_be_bytes).collect::<Vec<u8>>();
+
+    let (color_type, channels, samples) = match (img.color(), wide) {
+        (ColorType::L8 | ColorType::L16, false) => (0, 1, img.to_luma8().into_raw()),
+        (ColorType::L8 | ColorType::L16, true) => (0, 1, to_be_bytes(img.to_luma16().into_raw())),
+        (ColorType::La8 | ColorType::La16, false) => (4, 2, img.to_luma_alpha8().into_raw()),
+        (ColorType::La8 | ColorType::La16, true) => (4, 2, to_be_bytes(img.to_luma_alpha16().into_raw())),
+        (color, false) if !color.has_alpha() => (2, 3, img.to_rgb8().into_raw()),
+        (color, true) if !color.has_alpha() => (2, 3, to_be_bytes(img.to_rgb16().into_raw())),
+        (_, false) => (6, 4, img.to_rgba8().into_raw()),
+        (_, true) => (6, 4, to_be_bytes(img.to_rgba16().into_raw())),
+    };
+
+    let bit_depth = if wide { 16 } else { 8 };
+    let header = PngHeader { width, height, bit_depth, color_type };
+    Ok(write_interlaced_png(&header, &[], &samples, channels * usize::from(bit_depth / 8), encoder))
+}
+
+/// Writes an Adam7 PNG. `pixels` holds `bytes_per_pixel` bytes per pixel, or
+/// one palette index per byte when the bit depth is below 8.
+pub(crate) fn write_interlaced_png(
+    header: &PngHeader,
+    chunks: &[(&[u8; 4], Vec<u8>)],
+    pixels: &[u8],
+    bytes_per_pixel: usize,
+    encoder: &EncoderArgs,
+) -> Vec<u8> {
+    use std::io::Write;
+
+    let (width, height) = (header.width as usize, header.height as usize);
+    let bits = usize::from(header.bit_depth);
+    let mut raw = Vec::with_capacity(pixels.len() + height * 2);
+
+    for (x0, y0, dx, dy) in ADAM7_PASSES {
+        let pass_width = width.saturating_sub(x0).div_ceil(dx);
+        let pass_height = height.saturating_sub(y0).div_ceil(dy);
+        // Empty passes contribute no scanlines at all
+        if pass_width == 0 || pass_height == 0 {
+            continue;
+        }
+
+        let mut prev: Vec<u8> = Vec::new();
+        for y in (y0..height).step_by(dy) {
+            let row: Vec<u8> = if bits < 8 {
+                let per_byte = 8 / bits;
+                let indices: Vec<u8> = (x0..width).step_by(dx).map(|x| pixels[y * width + x]).collect();
+                indices.chunks(per_byte)
+                    .map(|group| group.iter().enumerate().fold(0u8, |byte, (i, &index)| byte | index << (8 - bits * (i + 1))))
+                    .collect()
+            } else {
+                (x0..width).step_by(dx)
+                    .flat_map(|x| &pixels[(y * width + x) * bytes_per_pixel..][..bytes_per_pixel])
+                    .copied()
+                    .collect()
+            };
+            if prev.is_empty() {
+                prev = vec![0; row.len()];
+            }
+            filter_scanline(&row, &prev, bytes_per_pixel.max(1), encoder.png_filter, &mut raw);
+            prev = row;
+        }
+    }
+
+    let level = flate2::Compression::new(encoder.png_compression.zlib_level());
+    let mut zlib = flate2::write::ZlibEncoder::new(Vec::new(), level);
+    // Writing into a Vec cannot fail
+    let _ = zlib.write_all(&raw);
+    let idat = zlib.finish().unwrap_or_default();
+
+    let mut ihdr = Vec::with_capacity(13);
+    ihdr.extend_from_slice(&header.width.to_be_bytes());
+    ihdr.extend_from_slice(&header.height.to_be_bytes());
+    ihdr.extend_from_slice(&[header.bit_depth, header.color_type, 0, 0, 1]); // deflate, adaptive filtering, Adam7
+
+    let mut out = Vec::with_capacity(idat.len() + 1024);
+    out.extend_from_slice(PNG_SIGNATURE);
+    push_png_chunk(&mut out, b"IHDR", &ihdr);
+    for (kind, data) in chunks {
+        push_png_chunk(&mut out, kind, data);
+    }
+    push_png_chunk(&mut out, b"IDAT", &idat);
+    push_png_chunk(&mut out, b"IEND", &[]);
+    out
+}
+
+/// Appends the filter type byte and filtered scanline. Adaptive filtering
+/// picks the filter whose output has the smallest sum of absolute values.
+pub(crate) fn filter_scanline(row: &[u8], prev: &[u8], bpp: usize, filter: PngFilter, out: &mut Vec<u8>) {
+    let left = |line: &[u8], i: usize| if i >= bpp { line[i - bpp] } else { 0 };
+    let paeth = |a: u8, b: u8, c: u8| {
+        let p = i16::from(a) + i16::from(b) - i16::from(c);
+        let (pa, pb, pc) = ((p - i16::from(a)).abs(), (p - i16::from(b)).abs(), (p - i16::from(c)).abs());
+        if pa <= pb && pa <= pc { a } else if pb <= pc { b } else { c }
+    };
+    let apply = |kind: u8| -> Vec<u8> {
+        (0..row.len())
+            .map(|i| {
+                let (a, b, c) = (left(row, i), prev[i], left(prev, i));
+                let predicted = match kind {
+                    0 => 0,
+                    1 => a,
+                    2 => b,
+                    3 => ((u16::from(a) + u16::from(b)) / 2) as u8,
+                    _ => paeth(a, b, c),
+                };
+                row[i].wrapping_sub(predicted)
+            })
+            .collect()
+    };
+    let cost = |line: &[u8]| line.iter().map(|&v| u32::from((v as i8).unsigned_abs())).sum::<u32>();
+
+    let (kind, line) = match filter.row_filter() {
+        Some(kind) => (kind, apply(kind)),
+        None => (0..5u8)
+            .map(|kind| (kind, apply(kind)))
+            .min_by_key(|(_, line)| cost(line))
+            .unwrap_or_else(|| (0, row.to_vec())),
+    };
+    out.push(kind);
+    out.extend_from_slice(&line);
+}
+
+pub(crate) fn push_png_chunk(out: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = out.len();
+    out.extend_from_slice(kind);
+    out.extend_from_slice(data);
+    let crc = crc32fast::hash(&out[start..]);
+    out.extend_from_slice(&crc.to_be_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::palette::{write_palette_png, Quantized};
+    use crate::test_util::encoder_args;
+
+    /// 1x1 and 2x2 leave most passes empty; the rest stop mid-block.
+    const SIZES: [(u32, u32); 8] = [(1, 1), (2, 2), (3, 5), (7, 9), (8, 8), (9, 1), (1, 9), (17, 11)];
+
+    fn sample(x: u32, y: u32, channel: u32) -> u16 {
+        (x.wrapping_mul(40_503) ^ y.wrapping_mul(2_654_435_761) ^ channel.wrapping_mul(97)) as u16
+    }
+
+    fn assert_interlaced_round_trip(img: &image::DynamicImage) {
+        let data = compress_png_interlaced(img, &encoder_args(&["--png-interlace"])).unwrap();
+        assert_eq!(data[28], 1, "IHDR interlace method");
+        assert_eq!(&image::load_from_memory(&data).unwrap(), img, "{:?} {}x{}", img.color(), img.width(), img.height());
+    }
+
+    #[test]
+    fn interlaced_round_trip_8_bit() {
+        for (width, height) in SIZES {
+            let rgba = image::RgbaImage::from_fn(width, height, |x, y| {
+                image::Rgba([0, 1, 2, 3].map(|c| sample(x, y, c) as u8))
+            });
+            let rgba = image::DynamicImage::ImageRgba8(rgba);
+            assert_interlaced_round_trip(&rgba);
+            assert_interlaced_round_trip(&image::DynamicImage::ImageRgb8(rgba.to_rgb8()));
+            assert_interlaced_round_trip(&image::DynamicImage::ImageLuma8(rgba.to_luma8()));
+            assert_interlaced_round_trip(&image::DynamicImage::ImageLumaA8(rgba.to_luma_alpha8()));
+        }
+    }
+
+    #[test]
+    fn interlaced_round_trip_16_bit() {
+        for (width, height) in SIZES {
+            let rgba = image::ImageBuffer::from_fn(width, height, |x, y| image::Rgba([0, 1, 2, 3].map(|c| sample(x, y, c))));
+            let rgba = image::DynamicImage::ImageRgba16(rgba);
+            assert_interlaced_round_trip(&rgba);
+            assert_interlaced_round_trip(&image::DynamicImage::ImageRgb16(rgba.to_rgb16()));
+            assert_interlaced_round_trip(&image::DynamicImage::ImageLuma16(rgba.to_luma16()));
+            assert_interlaced_round_trip(&image::DynamicImage::ImageLumaA16(rgba.to_luma_alpha16()));
+        }
+    }
+
+    #[test]
+    fn interlaced_round_trip_sub_byte_palettes() {
+        let encoder = encoder_args(&["--png-interlace"]);
+        for (colors, bits) in [(2, 1), (4, 2), (16, 4)] {
+            let palette: Vec<[u8; 4]> = (0..colors).map(|i| [i * 15, 255 - i * 15, i * 7, 255]).collect();
+            for (width, height) in SIZES {
+                let indices = (0..height)
+                    .flat_map(|y| (0..width).map(move |x| (sample(x, y, 0) % u16::from(colors)) as u8))
+                    .collect();
+                let quantized = Quantized { width, height, palette: palette.clone(), indices };
+                let data = write_palette_png(&quantized, &encoder).unwrap();
+                assert_eq!((data[24], data[25], data[28]), (bits, 3, 1), "IHDR depth, color type, interlace");
+
+                let expected = image::RgbImage::from_fn(width, height, |x, y| {
+                    let [r, g, b, _] = palette[usize::from(quantized.indices[(y * width + x) as usize])];
+                    image::Rgb([r, g, b])
+                });
+                assert_eq!(image::load_from_memory(&data).unwrap().to_rgb8(), expected, "{} bits, {}x{}", bits, width, height);
+            }
+        }
+    }
+}
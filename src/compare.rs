@@ -0,0 +1,132 @@
+//! The `compare` subcommand.
+
+use anyhow::{Context, Result};
+use colored::Colorize;
+use comfy_table::Table;
+use humansize::{format_size, DECIMAL};
+use rayon::prelude::*;
+use std::fs;
+
+use crate::cli::{is_valid_quality, validate_encoder_args, CompareArgs};
+use crate::codec::encode_resolved;
+use crate::report::print_banner;
+
+pub(crate) fn run_compare(args: &CompareArgs) -> Result<()> {
+    if args.quality.is_empty() || !args.quality.iter().copied().all(is_valid_quality) {
+        anyhow::bail!("Quality must be above 0 and at most 100");
+    }
+    validate_encoder_args(&args.encoder, &args.format)?;
+
+    print_banner();
+
+    let original_size = fs::metadata(&args.input)?.len();
+    let img = image::open(&args.input)
+        .with_context(|| format!("Failed to open image: {}", args.input.display()))?;
+    let reference = img.to_luma8();
+
+    if let Some(output_dir) = &args.output {
+        fs::create_dir_all(output_dir).with_context(|| {
+            format!("Failed to create output directory: {}", output_dir.display())
+        })?;
+    }
+
+    let stem = args.input.file_stem()
+        .and_then(|s| s.to_str())
+        .ok_or_else(|| anyhow::anyhow!("Invalid filename: {}", args.input.display()))?;
+
+    let candidates = args.quality.par_iter()
+        .map(|&quality| {
+            let (format, data) = encode_resolved(&img, &args.format, Some(quality), &args.input, &args.encoder)
+                .with_context(|| format!("Failed to encode at quality {}", quality))?;
+            let decoded = image::load_from_memory(&data)
+                .with_context(|| format!("Failed to decode candidate at quality {}", quality))?;
+            let score = ssim(&reference, &decoded.to_luma8());
+            Ok((quality, format, data, score))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut table = Table::new();
+    table.set_header(vec!["Quality", "Format", "Size", "Savings", "SSIM"]);
+
+    for (quality, format, data, score) in candidates {
+        let size = data.len() as u64;
+
+        if let Some(output_dir) = &args.output {
+            let path = output_dir.join(format!("{}-q{}.{}", stem, quality, format.extension()));
+            fs::write(&path, &data)
+                .with_context(|| format!("Failed to write candidate: {}", path.display()))?;
+        }
+
+        let savings = if size < original_size {
+            format!("{:.1}%", (original_size - size) as f64 / original_size as f64 * 100.0)
+        } else {
+            format!("-{:.1}%", (size - original_size) as f64 / original_size.max(1) as f64 * 100.0)
+        };
+        table.add_row(vec![
+            quality.to_string(),
+            format.extension().to_uppercase(),
+            format_size(size, DECIMAL),
+            savings,
+            score.map_or_else(|| "n/a".to_string(), |s| format!("{:.4}", s)),
+        ]);
+    }
+
+    println!("Original: {}", format_size(original_size, DECIMAL).bright_cyan());
+    println!("{}", table);
+    Ok(())
+}
+
+/// Mean structural similarity of two grayscale images over 8x8 windows.
+/// Returns `None` when the dimensions differ.
+pub(crate) fn ssim(a: &image::GrayImage, b: &image::GrayImage) -> Option<f64> {
+    const WINDOW: u32 = 8;
+    const STEP: u32 = 4;
+    const C1: f64 = (0.01 * 255.0) * (0.01 * 255.0);
+    const C2: f64 = (0.03 * 255.0) * (0.03 * 255.0);
+
+    if a.dimensions() != b.dimensions() {
+        return None;
+    }
+    let (width, height) = a.dimensions();
+    // Tiny images get a single window covering everything
+    let window_w = WINDOW.min(width);
+    let window_h = WINDOW.min(height);
+    if window_w == 0 || window_h == 0 {
+        return None;
+    }
+
+    let mut total = 0.0;
+    let mut windows = 0u64;
+    let mut y = 0;
+    while y + window_h <= height {
+        let mut x = 0;
+        while x + window_w <= width {
+            let n = f64::from(window_w * window_h);
+            let (mut sum_a, mut sum_b, mut sum_aa, mut sum_bb, mut sum_ab) = (0.0, 0.0, 0.0, 0.0, 0.0);
+            for wy in y..y + window_h {
+                for wx in x..x + window_w {
+                    let pa = f64::from(a.get_pixel(wx, wy)[0]);
+                    let pb = f64::from(b.get_pixel(wx, wy)[0]);
+                    sum_a += pa;
+                    sum_b += pb;
+                    sum_aa += pa * pa;
+                    sum_bb += pb * pb;
+                    sum_ab += pa * pb;
+                }
+            }
+            let mean_a = sum_a / n;
+            let mean_b = sum_b / n;
+            let var_a = sum_aa / n - mean_a * mean_a;
+            let var_b = sum_bb / n - mean_b * mean_b;
+            let covar = sum_ab / n - mean_a * mean_b;
+
+            total += ((2.0 * mean_a * mean_b + C1) * (2.0 * covar + C2))
+                / ((mean_a * mean_a + mean_b * mean_b + C1) * (var_a + var_b + C2));
+            windows += 1;
+            x += STEP;
+        }
+        y += STEP;
+    }
+
+    Some(total / windows as f64)
+}
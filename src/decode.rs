@@ -0,0 +1,417 @@
+//! Opening sources: decoding, orientation, colour profiles and CMYK JPEGs.
+
+use anyhow::{Context, Result};
+use image::metadata::Orientation;
+use std::{fs, io::Read, path::Path};
+
+use crate::cli::Args;
+use crate::error::EmptyImage;
+use crate::limits::{READ_SLOTS, ReadSlots};
+use crate::metadata::strip_tiff_thumbnail;
+use crate::similarity::dhash;
+
+/// A source decoded once for all of its outputs.
+pub(crate) struct DecodedSource {
+    pub(crate) img: image::DynamicImage,
+    pub(crate) icc: Option<Vec<u8>>,
+    pub(crate) metadata: SourceMetadata,
+    pub(crate) original_size: u64,
+    pub(crate) dhash: Option<u64>,
+    pub(crate) orientation: Option<Orientation>,
+}
+
+pub(crate) fn decode_source(input_path: &Path, args: &Args) -> Result<DecodedSource> {
+    let original_size = fs::metadata(input_path)?.len();
+    if original_size == 0 {
+        return Err(EmptyImage::NoBytes.into());
+    }
+
+    // Load image - always process, never skip
+    let (mut img, icc, orientation, mut metadata) = open_image(input_path, args.max_decoded_pixels, args.keep_metadata)
+        .with_context(|| format!("Failed to open image: {}", input_path.display()))?;
+    // Outside --keep-metadata outputs don't carry EXIF, so the rotation has to go into the pixels
+    if args.auto_orient {
+        img.apply_orientation(orientation);
+        // Otherwise viewers would rotate the embedded copy a second time
+        if let Some(exif) = &mut metadata.exif {
+            let _ = Orientation::remove_from_exif_chunk(exif);
+        }
+    }
+    if args.strip_thumbnail
+        && let Some(stripped) = metadata.exif.as_deref().and_then(strip_tiff_thumbnail)
+    {
+        metadata.exif = Some(stripped);
+    }
+    let (img, icc) = apply_icc_policy(img, icc, args)?;
+    let img = adjust_tones(img, args);
+    let dhash = args.find_similar.map(|_| dhash(&img));
+    let orientation = (orientation != Orientation::NoTransforms).then_some(orientation);
+    Ok(DecodedSource { img, icc, metadata, original_size, dhash, orientation })
+}
+
+/// Applies `--gamma`, then `--contrast`, then `--brightness` to every color
+/// channel, clamped to the valid range; alpha and the bit depth are kept.
+pub(crate) fn adjust_tones(mut img: image::DynamicImage, args: &Args) -> image::DynamicImage {
+    use image::DynamicImage;
+
+    if args.gamma.is_none() && args.brightness.is_none() && args.contrast.is_none() {
+        return img;
+    }
+    let inverse_gamma = 1.0 / args.gamma.unwrap_or(1.0);
+    let contrast = (100.0 + args.contrast.unwrap_or(0.0)) / 100.0;
+    let brightness = args.brightness.unwrap_or(0.0) / 100.0;
+    let curve = |value: f32| {
+        let value = value.clamp(0.0, 1.0).powf(inverse_gamma);
+        ((value - 0.5) * contrast + 0.5 + brightness).clamp(0.0, 1.0)
+    };
+    let lut8: Vec<u8> = (0..=255u8).map(|v| (curve(f32::from(v) / 255.0) * 255.0).round() as u8).collect();
+    let lut16 = || -> Vec<u16> {
+        (0..=u16::MAX).map(|v| (curve(f32::from(v) / 65535.0) * 65535.0).round() as u16).collect()
+    };
+
+    let has_alpha = img.color().has_alpha();
+    match &mut img {
+        DynamicImage::ImageLuma8(buf) => map_color_channels(buf, has_alpha, |v| lut8[usize::from(v)]),
+        DynamicImage::ImageLumaA8(buf) => map_color_channels(buf, has_alpha, |v| lut8[usize::from(v)]),
+        DynamicImage::ImageRgb8(buf) => map_color_channels(buf, has_alpha, |v| lut8[usize::from(v)]),
+        DynamicImage::ImageRgba8(buf) => map_color_channels(buf, has_alpha, |v| lut8[usize::from(v)]),
+        DynamicImage::ImageLuma16(buf) => {
+            let lut = lut16();
+            map_color_channels(buf, has_alpha, |v| lut[usize::from(v)]);
+        }
+        DynamicImage::ImageLumaA16(buf) => {
+            let lut = lut16();
+            map_color_channels(buf, has_alpha, |v| lut[usize::from(v)]);
+        }
+        DynamicImage::ImageRgb16(buf) => {
+            let lut = lut16();
+            map_color_channels(buf, has_alpha, |v| lut[usize::from(v)]);
+        }
+        DynamicImage::ImageRgba16(buf) => {
+            let lut = lut16();
+            map_color_channels(buf, has_alpha, |v| lut[usize::from(v)]);
+        }
+        DynamicImage::ImageRgb32F(buf) => map_color_channels(buf, has_alpha, curve),
+        DynamicImage::ImageRgba32F(buf) => map_color_channels(buf, has_alpha, curve),
+        other => {
+            let mut rgba = other.to_rgba8();
+            map_color_channels(&mut rgba, true, |v| lut8[usize::from(v)]);
+            *other = DynamicImage::ImageRgba8(rgba);
+        }
+    }
+    img
+}
+
+pub(crate) fn map_color_channels<P: image::Pixel>(
+    buf: &mut image::ImageBuffer<P, Vec<P::Subpixel>>,
+    has_alpha: bool,
+    f: impl Fn(P::Subpixel) -> P::Subpixel,
+) {
+    let color_channels = usize::from(P::CHANNEL_COUNT) - usize::from(has_alpha);
+    for pixel in buf.pixels_mut() {
+        for channel in &mut pixel.channels_mut()[..color_channels] {
+            *channel = f(*channel);
+        }
+    }
+}
+
+/// EXIF and XMP blocks read from the source for `--keep-metadata`.
+#[derive(Default)]
+pub(crate) struct SourceMetadata {
+    /// A raw TIFF block, without the JPEG `Exif\0\0` prefix
+    pub(crate) exif: Option<Vec<u8>>,
+    pub(crate) xmp: Option<Vec<u8>>,
+}
+
+/// Decodes an image along with its embedded ICC profile, if any, and its
+/// EXIF orientation. EXIF and XMP are only read when `keep_metadata` is set.
+pub(crate) fn open_image(
+    input_path: &Path,
+    max_decoded_pixels: Option<u64>,
+    keep_metadata: bool,
+) -> Result<(image::DynamicImage, Option<Vec<u8>>, Orientation, SourceMetadata)> {
+    use image::ImageDecoder;
+
+    // Only the read waits for a --max-concurrent-reads slot; decoding runs in parallel
+    let data = {
+        let _slot = READ_SLOTS.get().map(ReadSlots::acquire);
+        fs::read(input_path).with_context(|| format!("Failed to read image: {}", input_path.display()))?
+    };
+
+    if is_plain_cmyk_jpeg(input_path, &data) {
+        let img = decode_plain_cmyk(&data, max_decoded_pixels)?;
+        return Ok((img, None, Orientation::NoTransforms, SourceMetadata::default()));
+    }
+
+    let mut reader = image::ImageReader::new(std::io::Cursor::new(data));
+    match image::ImageFormat::from_path(input_path) {
+        Ok(format) => reader.set_format(format),
+        Err(_) => reader = reader.with_guessed_format()?,
+    }
+    if let Some(max_pixels) = max_decoded_pixels {
+        // Backstop for decoders that allocate before reporting dimensions;
+        // 16 bytes covers the widest pixel type (RGBA f32)
+        let mut limits = image::Limits::default();
+        limits.max_alloc = Some(max_pixels.saturating_mul(16));
+        reader.limits(limits);
+    }
+
+    let mut decoder = reader.into_decoder()?;
+    let (width, height) = decoder.dimensions();
+    if width == 0 || height == 0 {
+        return Err(EmptyImage::NoPixels { width, height }.into());
+    }
+    if let Some(max_pixels) = max_decoded_pixels {
+        check_decoded_pixels(u64::from(width), u64::from(height), max_pixels)?;
+    }
+
+    // A profile that can't be read is no reason to fail the image
+    let icc = decoder.icc_profile().ok().flatten();
+    let orientation = decoder.orientation().unwrap_or(Orientation::NoTransforms);
+    let metadata = if keep_metadata {
+        SourceMetadata {
+            exif: decoder.exif_metadata().ok().flatten(),
+            xmp: decoder.xmp_metadata().ok().flatten(),
+        }
+    } else {
+        SourceMetadata::default()
+    };
+    Ok((image::DynamicImage::from_decoder(decoder)?, icc, orientation, metadata))
+}
+
+/// `--to-srgb` converts tagged pixels and drops the profile, `--keep-icc`
+/// keeps it for the output, and otherwise it is discarded as before.
+pub(crate) fn apply_icc_policy(
+    img: image::DynamicImage,
+    icc: Option<Vec<u8>>,
+    args: &Args,
+) -> Result<(image::DynamicImage, Option<Vec<u8>>)> {
+    match icc {
+        Some(profile) if args.to_srgb => Ok((convert_to_srgb(img, &profile)?, None)),
+        Some(profile) if args.keep_icc => Ok((img, Some(profile))),
+        _ => Ok((img, None)),
+    }
+}
+
+/// Transforms pixels from an RGB ICC profile into sRGB, at 16 bits for
+/// sources deeper than 8 bits. Gray and CMYK profiles are left alone: the
+/// decoder has already turned CMYK into RGB without them.
+pub(crate) fn convert_to_srgb(img: image::DynamicImage, profile: &[u8]) -> Result<image::DynamicImage> {
+    use image::DynamicImage;
+    use moxcms::{ColorProfile, DataColorSpace, Layout, TransformOptions};
+
+    let source = ColorProfile::new_from_slice(profile).context("Failed to parse embedded ICC profile")?;
+    if source.color_space != DataColorSpace::Rgb {
+        return Ok(img);
+    }
+    let srgb = ColorProfile::new_srgb();
+    let color = img.color();
+    let layout = if color.has_alpha() { Layout::Rgba } else { Layout::Rgb };
+    let options = TransformOptions::default();
+    let failed = || "Failed to convert ICC profile to sRGB";
+
+    if color.bits_per_pixel() / u16::from(color.channel_count()) > 8 {
+        let transform = source.create_transform_16bit(layout, &srgb, layout, options).with_context(failed)?;
+        let convert = |src: &[u16], dst: &mut [u16]| transform.transform(src, dst).with_context(failed);
+        return Ok(if color.has_alpha() {
+            let mut out = img.to_rgba16();
+            convert(&img.to_rgba16(), &mut out)?;
+            DynamicImage::ImageRgba16(out)
+        } else {
+            let mut out = img.to_rgb16();
+            convert(&img.to_rgb16(), &mut out)?;
+            DynamicImage::ImageRgb16(out)
+        });
+    }
+
+    let transform = source.create_transform_8bit(layout, &srgb, layout, options).with_context(failed)?;
+    let convert = |src: &[u8], dst: &mut [u8]| transform.transform(src, dst).with_context(failed);
+    Ok(if color.has_alpha() {
+        let mut out = img.to_rgba8();
+        convert(&img.to_rgba8(), &mut out)?;
+        DynamicImage::ImageRgba8(out)
+    } else {
+        let mut out = img.to_rgb8();
+        convert(&img.to_rgb8(), &mut out)?;
+        DynamicImage::ImageRgb8(out)
+    })
+}
+
+pub(crate) fn check_decoded_pixels(width: u64, height: u64, max_pixels: u64) -> Result<()> {
+    use image::error::{ImageError, LimitError, LimitErrorKind};
+
+    let pixels = width * height;
+    if pixels > max_pixels {
+        let error = ImageError::Limits(LimitError::from_kind(LimitErrorKind::DimensionError));
+        return Err(anyhow::Error::new(error).context(format!(
+            "{}x{} is {} pixels, over the --max-decoded-pixels limit of {}",
+            width, height, pixels, max_pixels
+        )));
+    }
+    Ok(())
+}
+
+/// How a four-component JPEG stores its ink channels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum CmykLayout {
+    /// Adobe APP14 marker present: channels are stored inverted (CMYK or YCCK)
+    Adobe,
+    /// No APP14 marker: channels hold plain ink coverage
+    Plain,
+}
+
+/// Walks the JPEG marker segments up to the first scan and reports the CMYK
+/// layout, or `None` if the frame doesn't have four components.
+pub(crate) fn jpeg_cmyk_layout(mut reader: impl Read) -> Option<CmykLayout> {
+    let mut marker = [0u8; 2];
+    reader.read_exact(&mut marker).ok()?;
+    if marker != [0xFF, 0xD8] {
+        return None;
+    }
+
+    let mut adobe = false;
+    loop {
+        reader.read_exact(&mut marker).ok()?;
+        if marker[0] != 0xFF {
+            return None;
+        }
+        // Fill bytes may pad any marker
+        while marker[1] == 0xFF {
+            reader.read_exact(&mut marker[1..]).ok()?;
+        }
+        // Standalone markers carry no length
+        if matches!(marker[1], 0x01 | 0xD0..=0xD7) {
+            continue;
+        }
+        if matches!(marker[1], 0xD9 | 0xDA) {
+            return None;
+        }
+
+        let mut length = [0u8; 2];
+        reader.read_exact(&mut length).ok()?;
+        let payload_len = u64::from(u16::from_be_bytes(length)).checked_sub(2)?;
+
+        match marker[1] {
+            // Start-of-frame markers; C4, C8 and CC share the range but aren't frames
+            0xC0..=0xCF if !matches!(marker[1], 0xC4 | 0xC8 | 0xCC) => {
+                let mut header = [0u8; 6];
+                reader.read_exact(&mut header).ok()?;
+                return (header[5] == 4)
+                    .then_some(if adobe { CmykLayout::Adobe } else { CmykLayout::Plain });
+            }
+            0xEE => {
+                let mut payload = Vec::new();
+                reader.by_ref().take(payload_len).read_to_end(&mut payload).ok()?;
+                adobe |= payload.starts_with(b"Adobe");
+            }
+            _ => {
+                std::io::copy(&mut reader.by_ref().take(payload_len), &mut std::io::sink()).ok()?;
+            }
+        }
+    }
+}
+
+/// The JPEG decoder assumes Adobe's inverted CMYK convention, which turns
+/// CMYK files written without an APP14 marker into negatives.
+pub(crate) fn is_plain_cmyk_jpeg(path: &Path, data: &[u8]) -> bool {
+    image::ImageFormat::from_path(path).is_ok_and(|format| format == image::ImageFormat::Jpeg)
+        && jpeg_cmyk_layout(data) == Some(CmykLayout::Plain)
+}
+
+/// Decodes raw CMYK samples and converts them to RGB with the plain-ink
+/// convention: `R = (255 - C) * (255 - K) / 255`, and likewise for G and B.
+pub(crate) fn decode_plain_cmyk(data: &[u8], max_decoded_pixels: Option<u64>) -> Result<image::DynamicImage> {
+    use image::error::{DecodingError, ImageError};
+    use zune_core::{bytestream::ZCursor, colorspace::ColorSpace, options::DecoderOptions};
+
+    let decode_error =
+        |e: zune_jpeg::errors::DecodeErrors| ImageError::Decoding(DecodingError::new(image::ImageFormat::Jpeg.into(), e));
+
+    let options = DecoderOptions::default()
+        .jpeg_set_out_colorspace(ColorSpace::CMYK)
+        .set_strict_mode(false)
+        .set_max_width(usize::MAX)
+        .set_max_height(usize::MAX);
+    let mut decoder = zune_jpeg::JpegDecoder::new_with_options(ZCursor::new(data), options);
+    decoder.decode_headers().map_err(decode_error)?;
+
+    let (width, height) = decoder.dimensions().context("JPEG header has no dimensions")?;
+    if let Some(max_pixels) = max_decoded_pixels {
+        check_decoded_pixels(width as u64, height as u64, max_pixels)?;
+    }
+
+    let cmyk = decoder.decode().map_err(decode_error)?;
+    let rgb = cmyk
+        .chunks_exact(4)
+        .flat_map(|px| {
+            let white = 255 - u16::from(px[3]);
+            let ink = |c: u8| (((255 - u16::from(c)) * white + 127) / 255) as u8;
+            [ink(px[0]), ink(px[1]), ink(px[2])]
+        })
+        .collect();
+
+    image::RgbImage::from_raw(width as u32, height as u32, rgb)
+        .map(image::DynamicImage::ImageRgb8)
+        .context("Decoded CMYK data doesn't match the image dimensions")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::compress_jpeg;
+    use crate::test_util::noisy_rgb;
+
+    // The same 64x48 CMYK image, once with Adobe's APP14 marker and inverted
+    // channels and once without it, holding plain ink coverage
+    const ADOBE: &[u8] = include_bytes!("../tests/fixtures/cmyk_adobe.jpg");
+    const PLAIN: &[u8] = include_bytes!("../tests/fixtures/cmyk_plain.jpg");
+
+    #[test]
+    fn cmyk_layout_follows_adobe_marker() {
+        assert_eq!(jpeg_cmyk_layout(ADOBE), Some(CmykLayout::Adobe));
+        assert_eq!(jpeg_cmyk_layout(PLAIN), Some(CmykLayout::Plain));
+        assert_eq!(jpeg_cmyk_layout(&compress_jpeg(&noisy_rgb(8, 8), 80.0).unwrap()[..]), None);
+        assert_eq!(jpeg_cmyk_layout(&PLAIN[..PLAIN.len() / 4]), Some(CmykLayout::Plain));
+        assert_eq!(jpeg_cmyk_layout(&b"\xFF\xD8\xFF"[..]), None);
+
+        assert!(is_plain_cmyk_jpeg(Path::new("scan.JPG"), PLAIN));
+        assert!(!is_plain_cmyk_jpeg(Path::new("scan.jpg"), ADOBE));
+        assert!(!is_plain_cmyk_jpeg(Path::new("scan.png"), PLAIN));
+    }
+
+    #[test]
+    fn plain_cmyk_decodes_like_adobe_cmyk() {
+        let adobe = image::load_from_memory(ADOBE).unwrap().to_rgb8();
+        let plain = decode_plain_cmyk(PLAIN, None).unwrap();
+        assert_eq!(plain.to_rgb8(), adobe);
+        // What the generic decoder makes of it: a negative
+        assert_ne!(image::load_from_memory(PLAIN).unwrap().to_rgb8(), adobe);
+
+        assert!(decode_plain_cmyk(PLAIN, Some(64 * 48 - 1)).is_err());
+    }
+
+    #[test]
+    fn convert_to_srgb_maps_wide_gamut_pixels() {
+        use moxcms::ColorProfile;
+
+        let img = noisy_rgb(16, 8);
+        let srgb = ColorProfile::new_srgb().encode().unwrap();
+        let same = convert_to_srgb(img.clone(), &srgb).unwrap().to_rgb8();
+        assert!(same.pixels().zip(img.to_rgb8().pixels()).all(|(a, b)| (0..3).all(|c| a[c].abs_diff(b[c]) <= 1)));
+
+        // Display P3 primaries are wider, so the same values spread further in sRGB
+        let p3 = ColorProfile::new_display_p3().encode().unwrap();
+        let warm = image::DynamicImage::ImageRgba16(image::ImageBuffer::from_pixel(2, 2, image::Rgba([40000, 30000, 20000, 1000])));
+        let converted = convert_to_srgb(warm, &p3).unwrap();
+        let image::DynamicImage::ImageRgba16(converted) = converted else {
+            panic!("16-bit RGBA in, {:?} out", converted.color());
+        };
+        let [red, green, blue, alpha] = converted.get_pixel(0, 0).0;
+        assert!(red > 40000 && green < 30000 && blue < 20000, "{:?}", [red, green, blue]);
+        assert_eq!(alpha, 1000);
+
+        let gray = ColorProfile::new_gray_with_gamma(2.2).encode().unwrap();
+        assert_eq!(convert_to_srgb(img.clone(), &gray).unwrap(), img);
+        assert!(convert_to_srgb(img, b"not a real profile").is_err());
+    }
+}
@@ -0,0 +1,148 @@
+//! How a failed file is categorised and described.
+
+use humansize::{format_size, DECIMAL};
+use std::{path::PathBuf, time::Duration};
+
+/// Why a file failed, as counted in the summary.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ErrorCategory {
+    Decode,
+    Io,
+    Unsupported,
+    TooLarge,
+    Timeout,
+    PostCmd,
+    Grew,
+    Empty,
+    Other,
+}
+
+impl ErrorCategory {
+    pub(crate) const fn label(self) -> &'static str {
+        match self {
+            Self::Decode => "corrupt",
+            Self::Io => "io",
+            Self::Unsupported => "unsupported",
+            Self::TooLarge => "too-large",
+            Self::Timeout => "timeout",
+            Self::PostCmd => "post-cmd",
+            Self::Grew => "grew",
+            Self::Empty => "empty",
+            Self::Other => "other",
+        }
+    }
+
+    pub(crate) fn classify(error: &anyhow::Error) -> Self {
+        for cause in error.chain() {
+            if cause.downcast_ref::<TimedOut>().is_some() {
+                return Self::Timeout;
+            }
+            if cause.downcast_ref::<PostCmdFailed>().is_some() {
+                return Self::PostCmd;
+            }
+            if cause.downcast_ref::<Grew>().is_some() {
+                return Self::Grew;
+            }
+            if cause.downcast_ref::<EmptyImage>().is_some() {
+                return Self::Empty;
+            }
+            if let Some(image_error) = cause.downcast_ref::<image::ImageError>() {
+                return match image_error {
+                    image::ImageError::Decoding(_) => Self::Decode,
+                    image::ImageError::Unsupported(_) => Self::Unsupported,
+                    image::ImageError::IoError(_) => Self::Io,
+                    image::ImageError::Limits(_) => Self::TooLarge,
+                    _ => Self::Other,
+                };
+            }
+            if cause.downcast_ref::<std::io::Error>().is_some() {
+                return Self::Io;
+            }
+        }
+        Self::Other
+    }
+}
+
+/// `--no-grow` caught an encode larger than its source.
+#[derive(Debug)]
+pub(crate) struct Grew {
+    pub(crate) original_size: u64,
+    pub(crate) encoded_size: u64,
+}
+
+impl std::fmt::Display for Grew {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Output would grow from {} to {}; copied the original instead",
+            format_size(self.original_size, DECIMAL),
+            format_size(self.encoded_size, DECIMAL)
+        )
+    }
+}
+
+impl std::error::Error for Grew {}
+
+/// A source with nothing to compress, caught before a decoder or encoder
+/// gets to fail on it less helpfully.
+#[derive(Debug)]
+pub(crate) enum EmptyImage {
+    NoBytes,
+    NoPixels { width: u32, height: u32 },
+}
+
+impl std::fmt::Display for EmptyImage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NoBytes => write!(f, "File is empty (0 bytes)"),
+            Self::NoPixels { width, height } => write!(f, "Image has no pixels ({}x{})", width, height),
+        }
+    }
+}
+
+impl std::error::Error for EmptyImage {}
+
+#[derive(Debug)]
+pub(crate) struct TimedOut(pub(crate) Duration);
+
+impl std::fmt::Display for TimedOut {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Timed out after {}s", self.0.as_secs())
+    }
+}
+
+impl std::error::Error for TimedOut {}
+
+#[derive(Debug)]
+pub(crate) struct PostCmdFailed {
+    pub(crate) status: std::process::ExitStatus,
+    pub(crate) stderr: String,
+}
+
+impl std::fmt::Display for PostCmdFailed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.stderr.is_empty() {
+            write!(f, "--post-cmd failed with {}", self.status)
+        } else {
+            write!(f, "--post-cmd failed with {}: {}", self.status, self.stderr)
+        }
+    }
+}
+
+impl std::error::Error for PostCmdFailed {}
+
+/// A file that could not be compressed.
+#[derive(Debug, Clone)]
+pub struct FileError {
+    pub path: PathBuf,
+    pub category: ErrorCategory,
+    pub message: String,
+}
+
+/// Formats an error with its immediate cause, which is usually where the
+/// actual reason (truncated file, permission denied, ...) lives.
+pub(crate) fn describe_error(e: &anyhow::Error) -> String {
+    let message = e.chain().nth(1)
+        .map_or_else(|| e.to_string(), |cause| format!("{}: {}", e, cause));
+    message.trim_end().to_string()
+}
@@ -0,0 +1,64 @@
+//! PixelSqueeze: batch image compression behind the `pixelsqueeze` command line.
+
+mod analyze;
+mod bench;
+mod cli;
+mod codec;
+mod compare;
+mod decode;
+mod error;
+mod limits;
+mod metadata;
+mod output;
+mod pipeline;
+mod plan;
+mod preview;
+mod report;
+mod resize;
+mod similarity;
+mod timing;
+
+#[cfg(test)]
+mod test_util;
+
+use anyhow::Result;
+use clap::{CommandFactory, FromArgMatches};
+
+use crate::analyze::run_analyze;
+use crate::bench::run_bench;
+use crate::cli::{check_format_flags, given_flags, print_settings, with_default_subcommand, Cli, Command};
+use crate::compare::run_compare;
+use crate::pipeline::run_compress;
+
+pub use crate::cli::{Args, OutputFormat};
+pub use crate::codec::compress_jpeg;
+pub use crate::error::{ErrorCategory, FileError};
+pub use crate::pipeline::{compress_image_multi, compress_stream, FileOutcome, FileResult, OutputSpec, Variant};
+
+/// Runs the command line in `std::env::args_os()`.
+pub fn run() -> Result<()> {
+    let matches = Cli::command().get_matches_from(with_default_subcommand(std::env::args_os()));
+    let cli = Cli::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    let given = matches.subcommand().map(|(_, sub)| given_flags(sub)).unwrap_or_default();
+
+    match cli.command {
+        Command::Compress(args) => {
+            check_format_flags(&args.output_formats(), &given, args.strict, args.progress_json)?;
+            if args.print_settings
+                && let Some((name, sub)) = matches.subcommand()
+            {
+                print_settings(name, sub)?;
+            }
+            run_compress(&args)
+        }
+        Command::Analyze(args) => run_analyze(&args),
+        Command::Compare(args) => {
+            check_format_flags(std::slice::from_ref(&args.format), &given, false, false)?;
+            run_compare(&args)
+        }
+        Command::Bench(args) => {
+            check_format_flags(&args.formats, &given, false, false)?;
+            run_bench(&args)
+        }
+    }
+}
@@ -0,0 +1,85 @@
+//! Throttles on how hard a run leans on the machine.
+
+use std::{
+    sync::{Condvar, Mutex, OnceLock, PoisonError},
+    time::{Duration, Instant},
+};
+
+/// Rate limiter shared by all workers for `--max-throughput`. Each file
+/// reserves its size against the budget and sleeps until its slot comes up,
+/// so the batch averages at most the configured bytes per second.
+pub(crate) struct Throttle {
+    pub(crate) bytes_per_sec: f64,
+    pub(crate) next_slot: Mutex<Instant>,
+}
+
+impl Throttle {
+    pub(crate) fn new(bytes_per_sec: u64) -> Self {
+        Self { bytes_per_sec: bytes_per_sec as f64, next_slot: Mutex::new(Instant::now()) }
+    }
+
+    pub(crate) fn acquire(&self, bytes: u64) {
+        let wait = {
+            let Ok(mut next_slot) = self.next_slot.lock() else {
+                return;
+            };
+            let now = Instant::now();
+            let start = (*next_slot).max(now);
+            *next_slot = start + Duration::from_secs_f64(bytes as f64 / self.bytes_per_sec);
+            start - now
+        };
+        if !wait.is_zero() {
+            std::thread::sleep(wait);
+        }
+    }
+}
+
+/// Read slots for `--max-concurrent-reads`, set once before any work starts.
+pub(crate) static READ_SLOTS: OnceLock<ReadSlots> = OnceLock::new();
+
+/// A counting semaphore. On spinning disks and network shares, a handful of
+/// sequential-ish readers beats every worker seeking at once.
+pub(crate) struct ReadSlots {
+    pub(crate) free: Mutex<usize>,
+    pub(crate) released: Condvar,
+}
+
+impl ReadSlots {
+    pub(crate) fn new(slots: usize) -> Self {
+        Self { free: Mutex::new(slots), released: Condvar::new() }
+    }
+
+    pub(crate) fn acquire(&self) -> ReadSlot<'_> {
+        let mut free = self.free.lock().unwrap_or_else(PoisonError::into_inner);
+        while *free == 0 {
+            free = self.released.wait(free).unwrap_or_else(PoisonError::into_inner);
+        }
+        *free -= 1;
+        ReadSlot(self)
+    }
+}
+
+/// Held while reading; dropping it frees the slot.
+pub(crate) struct ReadSlot<'a>(pub(crate) &'a ReadSlots);
+
+impl Drop for ReadSlot<'_> {
+    fn drop(&mut self) {
+        *self.0.free.lock().unwrap_or_else(PoisonError::into_inner) += 1;
+        self.0.released.notify_one();
+    }
+}
+
+#[cfg(unix)]
+pub(crate) fn lower_priority(niceness: i32) -> std::io::Result<()> {
+    // SAFETY: setpriority only reads its integer arguments
+    if unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, niceness) } == 0 {
+        Ok(())
+    } else {
+        Err(std::io::Error::last_os_error())
+    }
+}
+
+#[cfg(not(unix))]
+pub(crate) fn lower_priority(_niceness: i32) -> std::io::Result<()> {
+    Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "not supported on this platform"))
+}
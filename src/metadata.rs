@@ -0,0 +1,844 @@
+//! Rewriting metadata inside encoded files: EXIF, XMP, ICC profiles and density.
+
+use std::collections::HashSet;
+
+use crate::cli::Args;
+use crate::codec::push_png_chunk;
+use crate::decode::SourceMetadata;
+use crate::pipeline::EncodedImage;
+
+pub(crate) fn strip_encoded_chunks(mut encoded: EncodedImage, args: &Args) -> EncodedImage {
+    if args.strip_chunks || !args.keep_metadata {
+        // Mostly matters for the grow-guard copy, which keeps the source bytes verbatim
+        if let Some(stripped) = strip_png_chunks(&encoded.data)
+            && stripped.len() < encoded.data.len()
+        {
+            encoded.data = stripped;
+        }
+    }
+    if args.keep_metadata
+        && args.strip_thumbnail
+        && let Some(stripped) = strip_jpeg_exif_thumbnail(&encoded.data)
+    {
+        encoded.data = stripped;
+    }
+    encoded
+}
+
+/// Rewrites the `Exif` APP1 segment of a JPEG without its IFD1 thumbnail.
+/// Returns `None` when there is no EXIF thumbnail to drop.
+pub(crate) fn strip_jpeg_exif_thumbnail(data: &[u8]) -> Option<Vec<u8>> {
+    const SIGNATURE: &[u8] = b"Exif\0\0";
+
+    if !data.starts_with(&[0xFF, 0xD8]) {
+        return None;
+    }
+    let mut out = Vec::with_capacity(data.len());
+    out.extend_from_slice(&[0xFF, 0xD8]);
+    let mut pos = 2;
+    let mut stripped = false;
+    // Headers run up to the first segment that isn't APPn or COM
+    while let [0xFF, marker @ (0xE0..=0xEF | 0xFE), ..] = *data.get(pos..)? {
+        let length = usize::from(u16::from_be_bytes([*data.get(pos + 2)?, *data.get(pos + 3)?]));
+        let segment = data.get(pos..pos + 2 + length)?;
+        pos += 2 + length;
+        let tiff = segment.get(4..).and_then(|payload| payload.strip_prefix(SIGNATURE));
+        match tiff.filter(|_| marker == 0xE1 && !stripped).and_then(strip_tiff_thumbnail) {
+            Some(tiff) => {
+                out.extend_from_slice(&[0xFF, 0xE1]);
+                out.extend_from_slice(&((2 + SIGNATURE.len() + tiff.len()) as u16).to_be_bytes());
+                out.extend_from_slice(SIGNATURE);
+                out.extend_from_slice(&tiff);
+                stripped = true;
+            }
+            None => out.extend_from_slice(segment),
+        }
+    }
+    out.extend_from_slice(&data[pos..]);
+    stripped.then_some(out)
+}
+
+/// Unlinks IFD1, the thumbnail directory, from an EXIF TIFF block, and cuts
+/// it and its image data off the end when nothing IFD0 keeps lies beyond them.
+pub(crate) fn strip_tiff_thumbnail(tiff: &[u8]) -> Option<Vec<u8>> {
+    const EXIF_IFD: u16 = 0x8769;
+    const GPS_IFD: u16 = 0x8825;
+    const INTEROP_IFD: u16 = 0xA005;
+    const STRIP_OFFSETS: u16 = 0x0111;
+    const THUMBNAIL_OFFSET: u16 = 0x0201;
+
+    let big_endian = match tiff.get(..2)? {
+        b"II" => false,
+        b"MM" => true,
+        _ => return None,
+    };
+    let u16_at = |at: usize| -> Option<u16> {
+        let bytes = tiff.get(at..at + 2)?.try_into().ok()?;
+        Some(if big_endian { u16::from_be_bytes(bytes) } else { u16::from_le_bytes(bytes) })
+    };
+    let u32_at = |at: usize| -> Option<usize> {
+        let bytes = tiff.get(at..at + 4)?.try_into().ok()?;
+        Some((if big_endian { u32::from_be_bytes(bytes) } else { u32::from_le_bytes(bytes) }) as usize)
+    };
+    // Entries as (tag, value or offset), plus the directory's byte span and
+    // the furthest byte its out-of-line values reach
+    let read_ifd = |offset: usize| -> Option<(Vec<(u16, usize)>, usize)> {
+        let count = usize::from(u16_at(offset)?);
+        let mut entries = Vec::with_capacity(count);
+        let mut end = offset + 2 + count * 12 + 4;
+        tiff.get(..end)?;
+        for entry in (0..count).map(|i| offset + 2 + i * 12) {
+            let (tag, kind, n) = (u16_at(entry)?, u16_at(entry + 2)?, u32_at(entry + 4)?);
+            let unit = match kind {
+                1 | 2 | 6 | 7 => 1,
+                3 | 8 => 2,
+                4 | 9 | 11 | 13 => 4,
+                5 | 10 | 12 => 8,
+                _ => 1,
+            };
+            let value = u32_at(entry + 8)?;
+            if n.checked_mul(unit)? > 4 {
+                end = end.max(value.checked_add(n * unit)?);
+            }
+            entries.push((tag, value));
+        }
+        Some((entries, end))
+    };
+
+    let ifd0 = u32_at(4)?;
+    let (entries, mut kept_end) = read_ifd(ifd0)?;
+    let link = ifd0 + 2 + entries.len() * 12;
+    let ifd1 = u32_at(link)?;
+    if ifd1 == 0 {
+        return None;
+    }
+
+    // IFD0 stays, along with the Exif, GPS and Interop directories it reaches
+    let mut pending: Vec<usize> = entries.iter()
+        .filter(|(tag, _)| matches!(*tag, EXIF_IFD | GPS_IFD))
+        .map(|&(_, offset)| offset)
+        .collect();
+    let mut visited = HashSet::new();
+    while let Some(offset) = pending.pop() {
+        if !visited.insert(offset) {
+            continue;
+        }
+        let (sub_entries, end) = read_ifd(offset)?;
+        kept_end = kept_end.max(end);
+        pending.extend(sub_entries.iter().filter(|(tag, _)| *tag == INTEROP_IFD).map(|&(_, offset)| offset));
+    }
+
+    let (thumb_entries, _) = read_ifd(ifd1)?;
+    let value_of = |tag: u16| thumb_entries.iter().find(|(t, _)| *t == tag).map(|&(_, value)| value);
+    let cut = value_of(THUMBNAIL_OFFSET).map_or(ifd1, |offset| offset.min(ifd1));
+
+    let mut out = tiff.to_vec();
+    out[link..link + 4].fill(0);
+    // Strip-based thumbnails can sit anywhere, so those are only unlinked
+    if value_of(STRIP_OFFSETS).is_none() && cut >= kept_end {
+        out.truncate(cut);
+    }
+    Some(out)
+}
+
+/// Embeds the profile kept by `--keep-icc`, replacing any the output already has.
+pub(crate) fn embed_icc_profile(mut encoded: EncodedImage, profile: Option<&[u8]>) -> EncodedImage {
+    if let Some(profile) = profile
+        && let Some(tagged) = set_icc_profile(&encoded.data, profile)
+    {
+        encoded.data = tagged;
+    }
+    encoded
+}
+
+/// Carries the source EXIF and XMP into fresh JPEG and WebP encodes under
+/// `--keep-metadata`. Grow-guard copies keep the source's own bytes instead.
+pub(crate) fn embed_source_metadata(data: Vec<u8>, metadata: &SourceMetadata) -> Vec<u8> {
+    let tagged = if data.starts_with(&[0xFF, 0xD8]) {
+        set_jpeg_metadata(&data, metadata)
+    } else if data.starts_with(b"RIFF") && data.get(8..12) == Some(b"WEBP") {
+        set_webp_metadata(&data, metadata)
+    } else {
+        None
+    };
+    tagged.unwrap_or(data)
+}
+
+/// Places `Exif` and XMP `APP1` segments after the JFIF header, replacing
+/// any existing ones. A block too large for one segment is left out.
+pub(crate) fn set_jpeg_metadata(data: &[u8], metadata: &SourceMetadata) -> Option<Vec<u8>> {
+    const EXIF_SIGNATURE: &[u8] = b"Exif\0\0";
+    const XMP_SIGNATURE: &[u8] = b"http://ns.adobe.com/xap/1.0/\0";
+
+    let mut app1 = Vec::new();
+    for (signature, payload) in [(EXIF_SIGNATURE, &metadata.exif), (XMP_SIGNATURE, &metadata.xmp)] {
+        let Some(payload) = payload else {
+            continue;
+        };
+        let Ok(length) = u16::try_from(2 + signature.len() + payload.len()) else {
+            continue;
+        };
+        app1.extend_from_slice(&[0xFF, 0xE1]);
+        app1.extend_from_slice(&length.to_be_bytes());
+        app1.extend_from_slice(signature);
+        app1.extend_from_slice(payload);
+    }
+    if app1.is_empty() {
+        return None;
+    }
+
+    let mut out = Vec::with_capacity(data.len() + app1.len());
+    out.extend_from_slice(&[0xFF, 0xD8]);
+    let mut pos = 2;
+    let mut inserted = false;
+    // Headers run up to the first segment that isn't APPn or COM
+    while let [0xFF, marker @ (0xE0..=0xEF | 0xFE), ..] = *data.get(pos..)? {
+        let length = usize::from(u16::from_be_bytes([*data.get(pos + 2)?, *data.get(pos + 3)?]));
+        let segment = data.get(pos..pos + 2 + length)?;
+        pos += 2 + length;
+        let payload = segment.get(4..).unwrap_or_default();
+        if marker == 0xE1 && (payload.starts_with(EXIF_SIGNATURE) || payload.starts_with(XMP_SIGNATURE)) {
+            continue;
+        }
+        if marker != 0xE0 && !inserted {
+            out.extend_from_slice(&app1);
+            inserted = true;
+        }
+        out.extend_from_slice(segment);
+    }
+    if !inserted {
+        out.extend_from_slice(&app1);
+    }
+    out.extend_from_slice(&data[pos..]);
+    Some(out)
+}
+
+/// Returns `data` carrying `profile`, or `None` for containers without an
+/// ICC slot (ICO, BMP, TGA, GIF) or malformed input.
+pub(crate) fn set_icc_profile(data: &[u8], profile: &[u8]) -> Option<Vec<u8>> {
+    if data.starts_with(PNG_SIGNATURE) {
+        set_png_icc_profile(data, profile)
+    } else if data.starts_with(&[0xFF, 0xD8]) {
+        set_jpeg_icc_profile(data, profile)
+    } else if data.starts_with(b"RIFF") && data.get(8..12) == Some(b"WEBP") {
+        set_webp_icc_profile(data, profile)
+    } else {
+        None
+    }
+}
+
+/// Places an `iCCP` chunk after `IHDR`, dropping any `sRGB` chunk, which
+/// must not appear alongside it.
+pub(crate) fn set_png_icc_profile(data: &[u8], profile: &[u8]) -> Option<Vec<u8>> {
+    use flate2::{write::ZlibEncoder, Compression};
+    use std::io::Write;
+
+    let mut iccp_data = b"ICC profile\0\0".to_vec(); // name, then compression method 0
+    let mut zlib = ZlibEncoder::new(&mut iccp_data, Compression::best());
+    zlib.write_all(profile).ok()?;
+    zlib.finish().ok()?;
+    let mut iccp = Vec::with_capacity(iccp_data.len() + 12);
+    push_png_chunk(&mut iccp, b"iCCP", &iccp_data);
+
+    let mut rest = data.strip_prefix(PNG_SIGNATURE)?;
+    let mut out = Vec::with_capacity(data.len() + iccp.len());
+    out.extend_from_slice(PNG_SIGNATURE);
+
+    while !rest.is_empty() {
+        let length = u32::from_be_bytes(rest.get(..4)?.try_into().ok()?) as usize;
+        let chunk_len = length.checked_add(12)?;
+        let chunk = rest.get(..chunk_len)?;
+        let chunk_type = &chunk[4..8];
+
+        if chunk_type != b"iCCP" && chunk_type != b"sRGB" {
+            out.extend_from_slice(chunk);
+        }
+        if chunk_type == b"IHDR" {
+            out.extend_from_slice(&iccp);
+        }
+
+        rest = &rest[chunk_len..];
+    }
+
+    Some(out)
+}
+
+/// Splits the profile across `APP2` `ICC_PROFILE` segments placed after
+/// the JFIF header, replacing any existing ones.
+pub(crate) fn set_jpeg_icc_profile(data: &[u8], profile: &[u8]) -> Option<Vec<u8>> {
+    const SIGNATURE: &[u8] = b"ICC_PROFILE\0";
+    // Segment length field, signature, then sequence number and count
+    const MAX_CHUNK: usize = 0xFFFF - 2 - SIGNATURE.len() - 2;
+
+    let chunks: Vec<&[u8]> = profile.chunks(MAX_CHUNK).collect();
+    let count = u8::try_from(chunks.len()).ok()?;
+    let mut app2 = Vec::with_capacity(profile.len() + chunks.len() * 18);
+    for (sequence, chunk) in (1..=count).zip(&chunks) {
+        app2.extend_from_slice(&[0xFF, 0xE2]);
+        app2.extend_from_slice(&((chunk.len() + SIGNATURE.len() + 4) as u16).to_be_bytes());
+        app2.extend_from_slice(SIGNATURE);
+        app2.extend_from_slice(&[sequence, count]);
+        app2.extend_from_slice(chunk);
+    }
+
+    let mut out = Vec::with_capacity(data.len() + app2.len());
+    out.extend_from_slice(&[0xFF, 0xD8]);
+    let mut pos = 2;
+    let mut inserted = false;
+    // Headers run up to the first segment that isn't APPn or COM
+    while let [0xFF, marker @ (0xE0..=0xEF | 0xFE), ..] = *data.get(pos..)? {
+        let length = usize::from(u16::from_be_bytes([*data.get(pos + 2)?, *data.get(pos + 3)?]));
+        let segment = data.get(pos..pos + 2 + length)?;
+        pos += 2 + length;
+        if marker == 0xE2 && segment.get(4..4 + SIGNATURE.len()) == Some(SIGNATURE) {
+            continue;
+        }
+        if marker != 0xE0 && !inserted {
+            out.extend_from_slice(&app2);
+            inserted = true;
+        }
+        out.extend_from_slice(segment);
+    }
+    if !inserted {
+        out.extend_from_slice(&app2);
+    }
+    out.extend_from_slice(&data[pos..]);
+    Some(out)
+}
+
+/// Adds an `ICCP` chunk, upgrading a simple (VP8 / VP8L only) file to the
+/// extended `VP8X` layout that can carry one.
+pub(crate) fn set_webp_icc_profile(data: &[u8], profile: &[u8]) -> Option<Vec<u8>> {
+    let (mut vp8x, others) = split_webp_chunks(data)?;
+    vp8x[8] |= 0x20; // ICC profile flag
+
+    let mut body = b"WEBP".to_vec();
+    body.extend_from_slice(&vp8x);
+    push_riff_chunk(&mut body, b"ICCP", profile);
+    for chunk in others.iter().filter(|chunk| !chunk.starts_with(b"ICCP")) {
+        body.extend_from_slice(chunk);
+    }
+    wrap_riff(body)
+}
+
+/// Appends the `EXIF` and `XMP ` chunks kept by `--keep-metadata`, replacing
+/// any the output already has; the spec places them after the image data.
+pub(crate) fn set_webp_metadata(data: &[u8], metadata: &SourceMetadata) -> Option<Vec<u8>> {
+    if metadata.exif.is_none() && metadata.xmp.is_none() {
+        return None;
+    }
+    let (mut vp8x, others) = split_webp_chunks(data)?;
+
+    let mut body = b"WEBP".to_vec();
+    body.extend_from_slice(&[0; 18]);
+    for chunk in others.iter().filter(|chunk| !chunk.starts_with(b"EXIF") && !chunk.starts_with(b"XMP ")) {
+        body.extend_from_slice(chunk);
+    }
+    if let Some(exif) = &metadata.exif {
+        vp8x[8] |= 0x08; // EXIF flag
+        push_riff_chunk(&mut body, b"EXIF", exif);
+    }
+    if let Some(xmp) = &metadata.xmp {
+        vp8x[8] |= 0x04; // XMP flag
+        push_riff_chunk(&mut body, b"XMP ", xmp);
+    }
+    body[4..22].copy_from_slice(&vp8x);
+    wrap_riff(body)
+}
+
+/// Splits a WebP file into its `VP8X` header, synthesized from the bitstream
+/// for simple files, and the chunks that follow it.
+pub(crate) fn split_webp_chunks(data: &[u8]) -> Option<(Vec<u8>, Vec<&[u8]>)> {
+    let mut chunks = Vec::new();
+    let mut rest = data.get(12..)?;
+    while !rest.is_empty() {
+        let size = u32::from_le_bytes(rest.get(4..8)?.try_into().ok()?) as usize;
+        let padded = size.checked_add(8 + size % 2)?;
+        chunks.push(rest.get(..padded.min(rest.len()))?);
+        rest = &rest[padded.min(rest.len())..];
+    }
+
+    let first = *chunks.first()?;
+    if first.starts_with(b"VP8X") {
+        return Some((first.get(..18)?.to_vec(), chunks.split_off(1)));
+    }
+    let payload = first.get(8..)?;
+    let (width, height, alpha) = if first.starts_with(b"VP8L") {
+        let bits = u32::from_le_bytes(payload.get(1..5)?.try_into().ok()?);
+        ((bits & 0x3FFF) + 1, ((bits >> 14) & 0x3FFF) + 1, (bits >> 28) & 1 == 1)
+    } else if first.starts_with(b"VP8 ") {
+        let width = u32::from(u16::from_le_bytes(payload.get(6..8)?.try_into().ok()?) & 0x3FFF);
+        let height = u32::from(u16::from_le_bytes(payload.get(8..10)?.try_into().ok()?) & 0x3FFF);
+        (width, height, false)
+    } else {
+        return None;
+    };
+    let mut vp8x = b"VP8X".to_vec();
+    vp8x.extend_from_slice(&10u32.to_le_bytes());
+    vp8x.extend_from_slice(&[if alpha { 0x10 } else { 0 }, 0, 0, 0]);
+    vp8x.extend_from_slice(&(width - 1).to_le_bytes()[..3]);
+    vp8x.extend_from_slice(&(height - 1).to_le_bytes()[..3]);
+    Some((vp8x, chunks))
+}
+
+/// Appends a RIFF chunk, padded to an even length.
+pub(crate) fn push_riff_chunk(body: &mut Vec<u8>, fourcc: &[u8; 4], payload: &[u8]) {
+    body.extend_from_slice(fourcc);
+    body.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    body.extend_from_slice(payload);
+    if payload.len() % 2 == 1 {
+        body.push(0);
+    }
+}
+
+pub(crate) fn wrap_riff(body: Vec<u8>) -> Option<Vec<u8>> {
+    let mut out = b"RIFF".to_vec();
+    out.extend_from_slice(&u32::try_from(body.len()).ok()?.to_le_bytes());
+    out.extend_from_slice(&body);
+    Some(out)
+}
+
+/// Writes `--dpi` into whichever resolution field the encoded container has.
+pub(crate) fn stamp_density(mut encoded: EncodedImage, args: &Args) -> EncodedImage {
+    if let Some(dpi) = args.dpi
+        && let Some(stamped) = set_density(&encoded.data, dpi)
+    {
+        encoded.data = stamped;
+    }
+    encoded
+}
+
+/// Returns `data` with its resolution metadata set to `dpi`, or `None` for
+/// containers without a density field (WebP, ICO, TGA) or malformed input.
+/// Only header bytes change; the pixel data is copied through untouched.
+pub(crate) fn set_density(data: &[u8], dpi: u16) -> Option<Vec<u8>> {
+    if data.starts_with(PNG_SIGNATURE) {
+        set_png_density(data, dpi)
+    } else if data.starts_with(&[0xFF, 0xD8]) {
+        set_jpeg_density(data, dpi)
+    } else if data.starts_with(b"BM") {
+        set_bmp_density(data, dpi)
+    } else {
+        None
+    }
+}
+
+pub(crate) fn dots_per_meter(dpi: u16) -> u32 {
+    (f64::from(dpi) / 0.0254).round() as u32
+}
+
+/// Replaces any `pHYs` chunk with one placed right after `IHDR`, as the spec requires.
+pub(crate) fn set_png_density(data: &[u8], dpi: u16) -> Option<Vec<u8>> {
+    let ppm = dots_per_meter(dpi).to_be_bytes();
+    let mut phys_data = [1u8; 9]; // trailing unit byte: meter
+    phys_data[..4].copy_from_slice(&ppm);
+    phys_data[4..8].copy_from_slice(&ppm);
+    let mut phys = Vec::with_capacity(21);
+    push_png_chunk(&mut phys, b"pHYs", &phys_data);
+
+    let mut rest = data.strip_prefix(PNG_SIGNATURE)?;
+    let mut out = Vec::with_capacity(data.len() + phys.len());
+    out.extend_from_slice(PNG_SIGNATURE);
+
+    while !rest.is_empty() {
+        let length = u32::from_be_bytes(rest.get(..4)?.try_into().ok()?) as usize;
+        let chunk_len = length.checked_add(12)?;
+        let chunk = rest.get(..chunk_len)?;
+        let chunk_type = &chunk[4..8];
+
+        if chunk_type != b"pHYs" {
+            out.extend_from_slice(chunk);
+        }
+        if chunk_type == b"IHDR" {
+            out.extend_from_slice(&phys);
+        }
+
+        rest = &rest[chunk_len..];
+    }
+
+    Some(out)
+}
+
+/// Updates the JFIF `APP0` density, inserting a JFIF segment when the file has none.
+pub(crate) fn set_jpeg_density(data: &[u8], dpi: u16) -> Option<Vec<u8>> {
+    let density = dpi.to_be_bytes();
+    let mut out = data.to_vec();
+
+    // SOI, APP0 marker, length, "JFIF\0", version, then units and X/Y density
+    if data.get(2..4) == Some(&[0xFF, 0xE0]) && data.get(6..11) == Some(b"JFIF\0") {
+        let fields = out.get_mut(13..18)?;
+        fields[0] = 1; // unit: dots per inch
+        fields[1..3].copy_from_slice(&density);
+        fields[3..5].copy_from_slice(&density);
+    } else {
+        let mut app0 = vec![0xFF, 0xE0, 0x00, 0x10];
+        app0.extend_from_slice(b"JFIF\0");
+        app0.extend_from_slice(&[1, 1, 1]);
+        app0.extend_from_slice(&density);
+        app0.extend_from_slice(&density);
+        app0.extend_from_slice(&[0, 0]); // no thumbnail
+        out.splice(2..2, app0);
+    }
+
+    Some(out)
+}
+
+/// Patches the pixels-per-meter fields of the `BITMAPINFOHEADER`.
+pub(crate) fn set_bmp_density(data: &[u8], dpi: u16) -> Option<Vec<u8>> {
+    let header_size = u32::from_le_bytes(data.get(14..18)?.try_into().ok()?);
+    if header_size < 40 {
+        return None;
+    }
+
+    let ppm = dots_per_meter(dpi).to_le_bytes();
+    let mut out = data.to_vec();
+    out.get_mut(38..42)?.copy_from_slice(&ppm);
+    out.get_mut(42..46)?.copy_from_slice(&ppm);
+    Some(out)
+}
+
+pub(crate) const PNG_SIGNATURE: &[u8; 8] = b"\x89PNG\r\n\x1a\n";
+
+// Ancillary chunks that affect how pixels render and so must survive stripping
+pub(crate) const PNG_KEPT_ANCILLARY: &[&[u8; 4]] = &[
+    b"tRNS", b"gAMA", b"cHRM", b"sRGB", b"iCCP", b"cICP", b"sBIT", b"acTL", b"fcTL", b"fdAT",
+];
+
+/// Returns the PNG with non-essential ancillary chunks removed, or `None` if
+/// `data` is not a well-formed PNG.
+pub(crate) fn strip_png_chunks(data: &[u8]) -> Option<Vec<u8>> {
+    let mut rest = data.strip_prefix(PNG_SIGNATURE)?;
+    let mut out = Vec::with_capacity(data.len());
+    out.extend_from_slice(PNG_SIGNATURE);
+
+    while !rest.is_empty() {
+        let length = u32::from_be_bytes(rest.get(..4)?.try_into().ok()?) as usize;
+        let chunk_len = length.checked_add(12)?;
+        let chunk = rest.get(..chunk_len)?;
+        let chunk_type: &[u8; 4] = chunk[4..8].try_into().ok()?;
+
+        // Lowercase first letter marks an ancillary chunk
+        let is_critical = chunk_type[0].is_ascii_uppercase();
+        if is_critical || PNG_KEPT_ANCILLARY.contains(&chunk_type) {
+            out.extend_from_slice(chunk);
+        }
+
+        rest = &rest[chunk_len..];
+        if chunk_type == b"IEND" {
+            break;
+        }
+    }
+
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::compress_jpeg;
+    use crate::decode::open_image;
+    use crate::test_util::{encoder_args, noisy_rgb};
+    use image::metadata::Orientation;
+    use std::fs;
+
+    /// Little-endian EXIF with an orientation tag in IFD0, then IFD1 from byte
+    /// 26 pointing at a 200-byte JPEG thumbnail.
+    fn exif_with_thumbnail() -> Vec<u8> {
+        let mut tiff = b"II*\0".to_vec();
+        tiff.extend_from_slice(&8u32.to_le_bytes());
+        tiff.extend_from_slice(&1u16.to_le_bytes());
+        tiff.extend_from_slice(&[0x12, 0x01, 3, 0, 1, 0, 0, 0, 6, 0, 0, 0]);
+        tiff.extend_from_slice(&26u32.to_le_bytes());
+        tiff.extend_from_slice(&2u16.to_le_bytes());
+        tiff.extend_from_slice(&[0x01, 0x02, 4, 0, 1, 0, 0, 0, 56, 0, 0, 0]);
+        tiff.extend_from_slice(&[0x02, 0x02, 4, 0, 1, 0, 0, 0, 200, 0, 0, 0]);
+        tiff.extend_from_slice(&0u32.to_le_bytes());
+        tiff.extend_from_slice(&[0xFF, 0xD8]);
+        tiff.resize(56 + 198, 0x55);
+        tiff.extend_from_slice(&[0xFF, 0xD9]);
+        tiff
+    }
+
+    fn jpeg_exif(data: &[u8]) -> Vec<u8> {
+        use image::ImageDecoder;
+
+        let mut decoder = image::codecs::jpeg::JpegDecoder::new(std::io::Cursor::new(data)).unwrap();
+        decoder.exif_metadata().unwrap().expect("EXIF segment")
+    }
+
+    fn assert_thumbnail_dropped(exif: &[u8]) {
+        let mut expected = exif_with_thumbnail();
+        expected.truncate(26);
+        expected[22..26].fill(0);
+        assert_eq!(exif, expected);
+        assert_eq!(Orientation::from_exif_chunk(exif), Some(Orientation::Rotate90));
+    }
+
+    #[test]
+    fn strip_thumbnail_from_source_copy() {
+        let metadata = SourceMetadata { exif: Some(exif_with_thumbnail()), xmp: None };
+        let source = set_jpeg_metadata(&compress_jpeg(&noisy_rgb(40, 30), 80.0).unwrap(), &metadata).unwrap();
+        assert_eq!(jpeg_exif(&source), exif_with_thumbnail());
+
+        let stripped = strip_jpeg_exif_thumbnail(&source).unwrap();
+        assert_thumbnail_dropped(&jpeg_exif(&stripped));
+        assert_eq!(image::load_from_memory(&source).unwrap(), image::load_from_memory(&stripped).unwrap());
+    }
+
+    #[test]
+    fn strip_thumbnail_from_recompressed_jpeg() {
+        let metadata = SourceMetadata { exif: Some(exif_with_thumbnail()), xmp: None };
+        let source = set_jpeg_metadata(&compress_jpeg(&noisy_rgb(40, 30), 95.0).unwrap(), &metadata).unwrap();
+        let path = std::env::temp_dir().join(format!("pixelsqueeze-thumbnail-{}.jpg", std::process::id()));
+        fs::write(&path, &source).unwrap();
+        let opened = open_image(&path, None, true);
+        fs::remove_file(&path).unwrap();
+        let (img, _, orientation, metadata) = opened.unwrap();
+        assert_eq!(orientation, Orientation::Rotate90);
+
+        let exif = strip_tiff_thumbnail(metadata.exif.as_deref().unwrap()).unwrap();
+        let metadata = SourceMetadata { exif: Some(exif), xmp: None };
+        let output = embed_source_metadata(compress_jpeg(&img, 60.0).unwrap(), &metadata);
+        assert_thumbnail_dropped(&jpeg_exif(&output));
+    }
+
+    /// The chunks of a WebP file as (fourcc, payload), checking the RIFF size
+    /// field and that every odd-length payload is followed by a pad byte.
+    fn webp_chunks(data: &[u8]) -> Vec<(&[u8], &[u8])> {
+        assert_eq!(&data[..4], b"RIFF");
+        assert_eq!(&data[8..12], b"WEBP");
+        assert_eq!(u32::from_le_bytes(data[4..8].try_into().unwrap()) as usize, data.len() - 8);
+        let mut chunks = Vec::new();
+        let mut rest = &data[12..];
+        while !rest.is_empty() {
+            let size = u32::from_le_bytes(rest[4..8].try_into().unwrap()) as usize;
+            chunks.push((&rest[..4], &rest[8..8 + size]));
+            let padded = 8 + size + size % 2;
+            assert!(padded <= rest.len(), "chunk {:?} overruns the file", &rest[..4]);
+            rest = &rest[padded..];
+        }
+        chunks
+    }
+
+    fn webp_exif(data: &[u8]) -> Option<Vec<u8>> {
+        use image::ImageDecoder;
+
+        let mut decoder = image::codecs::webp::WebPDecoder::new(std::io::Cursor::new(data)).unwrap();
+        decoder.exif_metadata().unwrap()
+    }
+
+    #[test]
+    fn webp_metadata_promotes_simple_lossy_file() {
+        let img = noisy_rgb(37, 29);
+        let source = crate::codec::compress_webp(&img, 80.0, &encoder_args(&[])).unwrap();
+        assert_eq!(webp_chunks(&source)[0].0, b"VP8 ");
+
+        // Odd lengths on both, so each chunk needs a pad byte
+        let exif = b"II*\0\x08\0\0\0\0\0\0".to_vec();
+        let xmp = b"<x:xmpmeta/>\n".to_vec();
+        let metadata = SourceMetadata { exif: Some(exif.clone()), xmp: Some(xmp.clone()) };
+        let output = set_webp_metadata(&source, &metadata).unwrap();
+
+        let chunks = webp_chunks(&output);
+        let fourccs: Vec<&[u8]> = chunks.iter().map(|(fourcc, _)| *fourcc).collect();
+        assert_eq!(fourccs, [&b"VP8X"[..], b"VP8 ", b"EXIF", b"XMP "]);
+        let vp8x = chunks[0].1;
+        assert_eq!(vp8x[0], 0x08 | 0x04);
+        assert_eq!(&vp8x[4..7], &36u32.to_le_bytes()[..3]);
+        assert_eq!(&vp8x[7..10], &28u32.to_le_bytes()[..3]);
+        assert_eq!(chunks[2].1, exif);
+        assert_eq!(chunks[3].1, xmp);
+
+        assert_eq!(webp_exif(&output), Some(exif));
+        assert_eq!(image::load_from_memory(&output).unwrap(), image::load_from_memory(&source).unwrap());
+    }
+
+    #[test]
+    fn webp_metadata_keeps_alpha_and_icc_of_extended_file() {
+        let rgba = image::RgbaImage::from_fn(20, 12, |x, y| image::Rgba([x as u8 * 12, y as u8 * 20, 90, (x * 12) as u8]));
+        let source = crate::codec::compress_webp_alpha(&rgba, 80.0, true, 100).unwrap();
+        assert_eq!(webp_chunks(&source)[0].0, b"VP8L");
+        let source = set_webp_icc_profile(&source, b"not a real profile").unwrap();
+
+        let first = SourceMetadata { exif: Some(b"II*\0first".to_vec()), xmp: None };
+        let second = SourceMetadata { exif: Some(b"II*\0second".to_vec()), xmp: None };
+        let output = set_webp_metadata(&set_webp_metadata(&source, &first).unwrap(), &second).unwrap();
+
+        let chunks = webp_chunks(&output);
+        let fourccs: Vec<&[u8]> = chunks.iter().map(|(fourcc, _)| *fourcc).collect();
+        assert_eq!(fourccs, [&b"VP8X"[..], b"ICCP", b"VP8L", b"EXIF"]);
+        assert_eq!(chunks[0].1[0], 0x20 | 0x10 | 0x08);
+        assert_eq!(chunks[1].1, b"not a real profile");
+
+        assert_eq!(webp_exif(&output), Some(b"II*\0second".to_vec()));
+        let decoded = image::load_from_memory(&output).unwrap();
+        assert_eq!(decoded.to_rgba8(), rgba);
+    }
+
+    fn jpeg_segments(data: &[u8]) -> Vec<(u8, &[u8])> {
+        let mut segments = Vec::new();
+        let mut pos = 2;
+        while data[pos] == 0xFF && data[pos + 1] != 0xDA {
+            let length = usize::from(u16::from_be_bytes([data[pos + 2], data[pos + 3]]));
+            segments.push((data[pos + 1], &data[pos + 4..pos + 2 + length]));
+            pos += 2 + length;
+        }
+        segments
+    }
+
+    #[test]
+    fn jpeg_density_patches_jfif_in_place() {
+        let source = compress_jpeg(&noisy_rgb(24, 16), 80.0).unwrap();
+        assert_eq!(jpeg_segments(&source)[0].0, 0xE0);
+
+        let output = set_jpeg_density(&source, 300).unwrap();
+        assert_eq!(output.len(), source.len());
+        // Units and both densities, then everything else untouched
+        assert_eq!(&output[13..18], &[1, 0x01, 0x2C, 0x01, 0x2C]);
+        assert_eq!(&output[..13], &source[..13]);
+        assert_eq!(&output[18..], &source[18..]);
+    }
+
+    #[test]
+    fn jpeg_density_inserts_jfif_before_exif() {
+        let jpeg = compress_jpeg(&noisy_rgb(24, 16), 80.0).unwrap();
+        // Drop the encoder's JFIF segment so the Exif APP1 comes first
+        let app0_end = 4 + usize::from(u16::from_be_bytes([jpeg[4], jpeg[5]]));
+        let mut without_jfif = jpeg[..2].to_vec();
+        without_jfif.extend_from_slice(&jpeg[app0_end..]);
+        let metadata = SourceMetadata { exif: Some(exif_with_thumbnail()), xmp: None };
+        let source = set_jpeg_metadata(&without_jfif, &metadata).unwrap();
+        assert_eq!(jpeg_segments(&source)[0].0, 0xE1);
+
+        let output = set_jpeg_density(&source, 144).unwrap();
+        let segments = jpeg_segments(&output);
+        assert_eq!(segments[0], (0xE0, &b"JFIF\0\x01\x01\x01\0\x90\0\x90\0\0"[..]));
+        assert_eq!(segments[1].0, 0xE1);
+        assert_eq!(jpeg_exif(&output), exif_with_thumbnail());
+        assert_eq!(image::load_from_memory(&output).unwrap(), image::load_from_memory(&source).unwrap());
+    }
+
+    /// The chunks of a PNG file as (type, data), checking every CRC.
+    fn png_chunks(data: &[u8]) -> Vec<(&[u8], &[u8])> {
+        let mut chunks = Vec::new();
+        let mut rest = data.strip_prefix(PNG_SIGNATURE).unwrap();
+        while !rest.is_empty() {
+            let length = u32::from_be_bytes(rest[..4].try_into().unwrap()) as usize;
+            let (body, crc) = rest[4..8 + length + 4].split_at(4 + length);
+            assert_eq!(crc, crc32fast::hash(body).to_be_bytes(), "CRC of {:?}", &body[..4]);
+            chunks.push((&body[..4], &body[4..]));
+            rest = &rest[12 + length..];
+        }
+        chunks
+    }
+
+    #[test]
+    fn png_density_inserts_phys_after_ihdr() {
+        let mut source = Vec::new();
+        noisy_rgb(24, 16).write_to(&mut std::io::Cursor::new(&mut source), image::ImageFormat::Png).unwrap();
+
+        // Stamping twice must leave only the second density
+        let output = set_png_density(&set_png_density(&source, 300).unwrap(), 72).unwrap();
+
+        let chunks = png_chunks(&output);
+        let types: Vec<&[u8]> = chunks.iter().map(|(chunk_type, _)| *chunk_type).collect();
+        assert_eq!(&types[..3], [&b"IHDR"[..], b"pHYs", b"IDAT"]);
+        assert_eq!(types.iter().filter(|&&chunk_type| chunk_type == b"pHYs").count(), 1);
+        // 72 dpi is 2835 pixels per meter on both axes
+        assert_eq!(chunks[1].1, [0, 0, 0x0B, 0x13, 0, 0, 0x0B, 0x13, 1]);
+        assert_eq!(image::load_from_memory(&output).unwrap(), image::load_from_memory(&source).unwrap());
+    }
+
+    /// Stand-in profile bytes: the decoders hand them back without parsing.
+    fn fake_profile(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 7 % 251) as u8).collect()
+    }
+
+    fn png_bytes(img: &image::DynamicImage) -> Vec<u8> {
+        let mut data = Vec::new();
+        img.write_to(&mut std::io::Cursor::new(&mut data), image::ImageFormat::Png).unwrap();
+        data
+    }
+
+    #[test]
+    fn png_icc_profile_round_trips() {
+        use flate2::read::ZlibDecoder;
+        use image::ImageDecoder;
+        use std::io::Read;
+
+        let source = png_bytes(&noisy_rgb(24, 16));
+        // An sRGB chunk must give way to the profile
+        let mut srgb = Vec::new();
+        push_png_chunk(&mut srgb, b"sRGB", &[0]);
+        let ihdr_end = PNG_SIGNATURE.len() + 25;
+        let source = [&source[..ihdr_end], &srgb, &source[ihdr_end..]].concat();
+
+        let profile = fake_profile(5000);
+        let output = set_icc_profile(&set_icc_profile(&source, b"replaced").unwrap(), &profile).unwrap();
+
+        let chunks = png_chunks(&output);
+        let types: Vec<&[u8]> = chunks.iter().map(|(chunk_type, _)| *chunk_type).collect();
+        assert_eq!(&types[..3], [&b"IHDR"[..], b"iCCP", b"IDAT"]);
+        assert_eq!(types.iter().filter(|&&chunk_type| chunk_type == b"iCCP").count(), 1);
+        assert!(!types.contains(&&b"sRGB"[..]));
+
+        let compressed = chunks[1].1.strip_prefix(b"ICC profile\0\0").unwrap();
+        let mut inflated = Vec::new();
+        ZlibDecoder::new(compressed).read_to_end(&mut inflated).unwrap();
+        assert_eq!(inflated, profile);
+
+        let mut decoder = image::codecs::png::PngDecoder::new(std::io::Cursor::new(&output)).unwrap();
+        assert_eq!(decoder.icc_profile().unwrap(), Some(profile));
+        assert_eq!(image::load_from_memory(&output).unwrap(), image::load_from_memory(&source).unwrap());
+    }
+
+    #[test]
+    fn jpeg_icc_profile_splits_across_segments() {
+        use image::ImageDecoder;
+
+        let source = compress_jpeg(&noisy_rgb(24, 16), 80.0).unwrap();
+        // Two full segments and a partial third
+        let profile = fake_profile(150_000);
+        let output = set_icc_profile(&set_icc_profile(&source, b"replaced").unwrap(), &profile).unwrap();
+
+        let segments = jpeg_segments(&output);
+        assert_eq!(segments[0].0, 0xE0);
+        let app2: Vec<&[u8]> = segments.iter().filter(|(marker, _)| *marker == 0xE2).map(|(_, body)| *body).collect();
+        assert_eq!(app2.len(), 3);
+        let mut joined = Vec::new();
+        for (sequence, body) in (1..=3).zip(&app2) {
+            let body = body.strip_prefix(b"ICC_PROFILE\0").unwrap();
+            assert_eq!(body[..2], [sequence, 3]);
+            joined.extend_from_slice(&body[2..]);
+        }
+        assert_eq!(joined, profile);
+        assert!(app2[0].len() == app2[1].len() && app2[1].len() > app2[2].len());
+
+        let mut decoder = image::codecs::jpeg::JpegDecoder::new(std::io::Cursor::new(&output)).unwrap();
+        assert_eq!(decoder.icc_profile().unwrap(), Some(profile));
+        assert_eq!(image::load_from_memory(&output).unwrap(), image::load_from_memory(&source).unwrap());
+
+        // More than 255 segments can't be numbered
+        assert_eq!(set_jpeg_icc_profile(&source, &fake_profile(256 * 0xFFEF)), None);
+    }
+
+    #[test]
+    fn webp_icc_profile_round_trips() {
+        use image::ImageDecoder;
+
+        let source = crate::codec::compress_webp(&noisy_rgb(37, 29), 80.0, &encoder_args(&[])).unwrap();
+        // Odd length, so the chunk needs a pad byte
+        let profile = fake_profile(301);
+        let output = set_icc_profile(&set_icc_profile(&source, b"replaced").unwrap(), &profile).unwrap();
+
+        let chunks = webp_chunks(&output);
+        let fourccs: Vec<&[u8]> = chunks.iter().map(|(fourcc, _)| *fourcc).collect();
+        assert_eq!(fourccs, [&b"VP8X"[..], b"ICCP", b"VP8 "]);
+        assert_eq!(chunks[0].1[0], 0x20);
+        assert_eq!(chunks[1].1, profile);
+
+        let mut decoder = image::codecs::webp::WebPDecoder::new(std::io::Cursor::new(&output)).unwrap();
+        assert_eq!(decoder.icc_profile().unwrap(), Some(profile));
+        assert_eq!(image::load_from_memory(&output).unwrap(), image::load_from_memory(&source).unwrap());
+    }
+}
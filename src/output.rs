@@ -0,0 +1,170 @@
+//! Writing encoded outputs and whatever accompanies them on disk.
+
+use anyhow::{Context, Result};
+use sha2::{Digest, Sha256};
+use std::{fs, path::Path};
+
+use crate::cli::Args;
+use crate::error::{Grew, PostCmdFailed};
+use crate::pipeline::{with_retry, Compressed, EncodedImage};
+use crate::plan::{file_identity, original_copy_filename, Job};
+use crate::timing::{timed, Stage};
+
+/// Whether writing `data` to `output` would leave `source` as it is, because
+/// `output` is the source and already holds those bytes. Any other write onto
+/// the source is refused, which `--output-same-dir` relies on.
+pub(crate) fn is_unchanged_source(output: &Path, source: &Path, data: &[u8]) -> Result<bool> {
+    if file_identity(output) != file_identity(source) {
+        return Ok(false);
+    }
+    if fs::read(source).is_ok_and(|current| current == data) {
+        return Ok(true);
+    }
+    anyhow::bail!("Output would overwrite its source: {}; pass --suffix or write elsewhere", source.display())
+}
+
+/// Hard-links `target` at `link`, falling back to a copy across filesystems.
+pub(crate) fn link_or_copy(target: &Path, link: &Path) -> Result<()> {
+    if link.exists() {
+        fs::remove_file(link).with_context(|| format!("Failed to replace output: {}", link.display()))?;
+    }
+    if fs::hard_link(target, link).is_err() {
+        fs::copy(target, link).with_context(|| format!("Failed to copy output: {}", link.display()))?;
+    }
+    Ok(())
+}
+
+/// Under `--only-if-smaller`, an existing file at the output path (a prior
+/// run's result, or the source itself when compressing in place) wins
+/// unless the new encode is strictly smaller.
+pub(crate) fn keeps_existing(encoded: &EncodedImage, args: &Args) -> bool {
+    args.only_if_smaller
+        && fs::metadata(&encoded.output_path).is_ok_and(|existing| existing.len() <= encoded.data.len() as u64)
+}
+
+pub(crate) fn original_copy(job: &Job, output_dir: &Path, original_size: u64) -> Result<EncodedImage> {
+    let output_path = output_dir.join(original_copy_filename(job));
+    let data = fs::read(&job.input)
+        .with_context(|| format!("Failed to read original: {}", job.input.display()))?;
+    Ok(EncodedImage {
+        original_size,
+        output_path,
+        data,
+        variant: None,
+        dhash: None,
+        rejected_size: None,
+        quality: None,
+        orientation: None,
+    })
+}
+
+pub(crate) fn write_encoded(encoded: EncodedImage, source: &Path, args: &Args) -> Result<Compressed> {
+    // A kept original that lands on its own source is already in place
+    let in_place = is_unchanged_source(&encoded.output_path, source, &encoded.data)?;
+    if !in_place {
+        with_retry(args, || {
+            fs::write(&encoded.output_path, &encoded.data)
+                .with_context(|| format!("Failed to write output: {}", encoded.output_path.display()))
+        })?;
+    }
+
+    let mut data = encoded.data;
+    if let Some(template) = &args.post_cmd {
+        run_post_cmd(template, &encoded.output_path)?;
+        // The hook usually rewrites the file, so report what it left behind
+        data = with_retry(args, || {
+            fs::read(&encoded.output_path)
+                .with_context(|| format!("Failed to read output after --post-cmd: {}", encoded.output_path.display()))
+        })?;
+    }
+
+    // After the hook, which may have replaced the file
+    if args.preserve_mode || args.preserve_timestamps {
+        preserve_source_attributes(source, &encoded.output_path, args)?;
+    }
+
+    // Hash the exact bytes that were written
+    let sha256 = args.manifest.as_ref()
+        .map(|_| format!("{:x}", Sha256::digest(&data)));
+
+    Ok(Compressed {
+        original_size: encoded.original_size,
+        compressed_size: data.len() as u64,
+        output_path: encoded.output_path,
+        sha256,
+        variant: encoded.variant,
+        dhash: encoded.dhash,
+        quality: encoded.quality,
+        orientation: encoded.orientation,
+    })
+}
+
+/// Writes the output, then under `--no-grow` fails the file if the grow-guard
+/// had to substitute the original; the copy stays so the output set is complete.
+pub(crate) fn write_guarded(encoded: EncodedImage, source: &Path, args: &Args) -> Result<Compressed> {
+    let rejected = encoded.rejected_size;
+    let compressed = timed(Stage::Write, || write_encoded(encoded, source, args))?;
+    match rejected {
+        Some(encoded_size) if args.no_grow => Err(Grew { original_size: compressed.original_size, encoded_size }.into()),
+        _ => Ok(compressed),
+    }
+}
+
+/// Copies permission bits and/or access and modification times from the
+/// source onto a freshly written output.
+pub(crate) fn preserve_source_attributes(source: &Path, output_path: &Path, args: &Args) -> Result<()> {
+    let metadata = fs::metadata(source)
+        .with_context(|| format!("Failed to read source metadata: {}", source.display()))?;
+
+    // Times first: a read-only mode would stop the output being opened for writing
+    if args.preserve_timestamps {
+        let mut times = fs::FileTimes::new().set_modified(metadata.modified()?);
+        if let Ok(accessed) = metadata.accessed() {
+            times = times.set_accessed(accessed);
+        }
+        fs::File::options()
+            .write(true)
+            .open(output_path)
+            .and_then(|file| file.set_times(times))
+            .with_context(|| format!("Failed to set timestamps: {}", output_path.display()))?;
+    }
+
+    if args.preserve_mode {
+        fs::set_permissions(output_path, metadata.permissions())
+            .with_context(|| format!("Failed to set permissions: {}", output_path.display()))?;
+    }
+    Ok(())
+}
+
+/// Runs the `--post-cmd` template against one output. The template is split
+/// on whitespace and run without a shell, so paths never need quoting; the
+/// output path is appended when the template has no `{}`.
+pub(crate) fn run_post_cmd(template: &str, output_path: &Path) -> Result<()> {
+    use std::process::{Command, Stdio};
+
+    let path = output_path.to_string_lossy();
+    let mut words = template.split_whitespace().map(|word| word.replace("{}", &path));
+    let program = words.next().context("--post-cmd is empty")?;
+
+    let mut command = Command::new(&program);
+    command.args(words);
+    if !template.contains("{}") {
+        command.arg(output_path);
+    }
+
+    let output = command
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .output()
+        .with_context(|| format!("Failed to run --post-cmd program `{}`", program))?;
+
+    if !output.status.success() {
+        return Err(PostCmdFailed {
+            status: output.status,
+            stderr: String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        }
+        .into());
+    }
+    Ok(())
+}
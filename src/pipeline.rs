@@ -0,0 +1,909 @@
+//! Compressing jobs: decoding, encoding every requested output and tallying outcomes.
+
+use anyhow::{Context, Result};
+use colored::Colorize;
+use humansize::{format_size, DECIMAL};
+use image::metadata::Orientation;
+use indicatif::ProgressBar;
+use rayon::prelude::*;
+use std::{
+    borrow::Cow,
+    collections::HashSet,
+    fs,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc, Arc, Mutex, OnceLock, PoisonError,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::cli::{validate_args, Args, DedupeMode, OutputFormat};
+use crate::codec::{compress_with_smart_settings, encode_resolved};
+use crate::decode::{decode_source, DecodedSource};
+use crate::error::{describe_error, ErrorCategory, FileError, TimedOut};
+use crate::limits::{lower_priority, READ_SLOTS, ReadSlots, Throttle};
+use crate::metadata::{embed_icc_profile, embed_source_metadata, stamp_density, strip_encoded_chunks};
+use crate::output::{is_unchanged_source, keeps_existing, link_or_copy, original_copy, write_encoded, write_guarded};
+use crate::plan::{collect_image_files, create_output_filename, file_identity, has_fresh_output, number_jobs, output_filename, plan_jobs, relative_display_path, split_duplicates, walk_image_files, Duplicate, Job, StemClaims, WalkOptions};
+use crate::preview::show_preview;
+use crate::report::{create_progress_bar, create_scan_spinner, create_spinner, print_banner, print_dir_breakdown, print_errors, print_orientation_summary, print_results, print_similar, print_warning, write_manifest, write_srcset_manifest, CompressionStats, ProgressEvent, ResultLogEntry};
+use crate::resize::resize_image;
+use crate::timing::{print_stage_times, timed, STAGE_TIMES, Stage, StageTimes};
+
+/// One output written for an input file.
+#[derive(Debug, Clone)]
+pub struct FileResult {
+    pub filename: String,
+    pub original_size: u64,
+    pub compressed_size: u64,
+    pub source_path: PathBuf,
+    /// Under `--full-paths`, the file's path below its input root
+    pub relative_path: Option<PathBuf>,
+    pub output_path: PathBuf,
+    pub sha256: Option<String>,
+    pub variant: Option<Variant>,
+    pub duration: Duration,
+    pub dhash: Option<u64>,
+    pub orientation: Option<Orientation>,
+    /// Quality `--auto-quality` or `--quality-steps` settled on
+    pub quality: Option<f32>,
+}
+
+/// An encoded output held in memory until it is committed to disk.
+pub(crate) struct EncodedImage {
+    pub(crate) original_size: u64,
+    pub(crate) output_path: PathBuf,
+    pub(crate) data: Vec<u8>,
+    pub(crate) variant: Option<Variant>,
+    /// Perceptual hash of the decoded source, for `--find-similar`
+    pub(crate) dhash: Option<u64>,
+    /// Size of the encode the grow-guard discarded in favour of a source copy
+    pub(crate) rejected_size: Option<u64>,
+    /// Quality `--auto-quality` or `--quality-steps` settled on for this file
+    pub(crate) quality: Option<f32>,
+    /// The source's EXIF orientation, when it isn't the default
+    pub(crate) orientation: Option<Orientation>,
+}
+
+pub(crate) struct Compressed {
+    pub(crate) original_size: u64,
+    pub(crate) compressed_size: u64,
+    pub(crate) output_path: PathBuf,
+    pub(crate) sha256: Option<String>,
+    pub(crate) variant: Option<Variant>,
+    pub(crate) dhash: Option<u64>,
+    pub(crate) quality: Option<f32>,
+    pub(crate) orientation: Option<Orientation>,
+}
+
+/// Dimensions of one responsive `--widths` output.
+#[derive(Debug, Clone, Copy)]
+pub struct Variant {
+    pub width: u32,
+    pub height: u32,
+}
+
+/// What became of one input file.
+pub enum FileOutcome {
+    /// One result per output written (several with `--widths`)
+    Completed(Vec<FileResult>),
+    Skipped(PathBuf),
+    /// Never started because `--total-budget` was reached
+    OverBudget(PathBuf),
+    Failed(FileError),
+}
+
+/// The directory outputs are written below, created unless `--no-dir-create`.
+pub(crate) fn prepare_output_root(args: &Args) -> Result<PathBuf> {
+    let output_dir = args.output.as_deref()
+        .map_or_else(|| PathBuf::from("compressed"), PathBuf::from);
+
+    if args.no_dir_create {
+        if !args.output_same_dir && !output_dir.is_dir() {
+            anyhow::bail!("Output directory does not exist: {} (not created under --no-dir-create)", output_dir.display());
+        }
+    } else if !args.output_same_dir {
+        fs::create_dir_all(&output_dir).with_context(|| {
+            format!("Failed to create output directory: {}", output_dir.display())
+        })?;
+    }
+    Ok(output_dir)
+}
+
+pub(crate) fn run_compress(args: &Args) -> Result<()> {
+    let start_time = Instant::now();
+    
+    validate_args(args)?;
+
+    if args.list_only {
+        return list_matched_files(args);
+    }
+    
+    print_banner();
+
+    if let Some(niceness) = args.nice {
+        // Before the worker pool exists, so every worker inherits the priority
+        if let Err(e) = lower_priority(niceness) {
+            print_warning(&format!("--nice could not be applied: {}", e), args.progress_json);
+        }
+    }
+
+    if let Some(slots) = args.max_concurrent_reads {
+        let _ = READ_SLOTS.set(ReadSlots::new(slots));
+    }
+
+    if let Some(path) = &args.jsonl {
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("Failed to open --jsonl file: {}", path.display()))?;
+        let _ = RESULT_LOG.set(Mutex::new(file));
+    }
+
+    if args.profile {
+        // Sized to match init_thread_pool
+        let _ = STAGE_TIMES.set(StageTimes::new(num_cpus::get().max(1)));
+    }
+
+    let output_dir = prepare_output_root(args)?;
+
+    if args.stream {
+        let processing_start = Instant::now();
+        let stats = process_files_streaming(&output_dir, args);
+        if stats.is_empty() {
+            print_no_files_found();
+            return Ok(());
+        }
+        let processing_time = processing_start.elapsed();
+        return report_results(&stats, args, &output_dir, processing_time, start_time.elapsed());
+    }
+
+    let scan = if args.progress_json { ProgressBar::hidden() } else { create_scan_spinner() };
+    let walk = WalkOptions::from_args(args);
+    let files = collect_image_files(&args.input, &walk, &scan)?;
+
+    if files.is_empty() {
+        print_no_files_found();
+        return Ok(());
+    }
+
+    print_files_found(files.len());
+
+    let jobs = match args.number {
+        Some(start) => number_jobs(&files, start)?,
+        None => plan_jobs(&files, args.on_collision, |path| args.collision_dir(path))?,
+    };
+    let jobs = jobs.into_iter().map(|job| job.with_suffix(args)).collect();
+    let (jobs, duplicates) = match args.dedupe {
+        Some(_) => split_duplicates(jobs),
+        None => (jobs, Vec::new()),
+    };
+
+    let processing_start = Instant::now();
+    let stats = process_files_parallel(&jobs, duplicates, &output_dir, args)?;
+    let processing_time = processing_start.elapsed();
+
+    report_results(&stats, args, &output_dir, processing_time, start_time.elapsed())
+}
+
+pub(crate) fn report_results(
+    stats: &CompressionStats,
+    args: &Args,
+    output_dir: &Path,
+    processing_time: Duration,
+    total_time: Duration,
+) -> Result<()> {
+    print_results(stats, &args.columns, processing_time, total_time);
+    print_orientation_summary(stats, args);
+
+    if let Some(times) = STAGE_TIMES.get() {
+        print_stage_times(times, processing_time);
+    }
+
+    if args.group_by_dir {
+        print_dir_breakdown(stats, &args.input);
+    }
+
+    if let Some(threshold) = args.find_similar {
+        print_similar(stats, threshold);
+    }
+
+    if args.list_errors {
+        print_errors(stats);
+    }
+
+    if let Some(manifest_path) = &args.manifest {
+        write_manifest(stats, manifest_path)?;
+    }
+
+    if !args.widths.is_empty() {
+        write_srcset_manifest(stats, output_dir)?;
+    }
+
+    if let Some(mode) = args.preview {
+        show_preview(stats, mode, args.progress_json);
+    }
+
+    if args.fail_on_unsupported {
+        let undecodable = stats.errors.iter()
+            .filter(|error| matches!(error.category, ErrorCategory::Decode | ErrorCategory::Unsupported | ErrorCategory::Empty))
+            .count();
+        if undecodable > 0 {
+            anyhow::bail!("{} files could not be decoded (rejected by --fail-on-unsupported)", undecodable);
+        }
+    }
+
+    Ok(())
+}
+
+pub(crate) fn print_no_files_found() {
+    println!("{}", "No image files found".bright_red());
+}
+
+/// `--list-only`: paths on stdout so they can be piped, the count on stderr.
+pub(crate) fn list_matched_files(args: &Args) -> Result<()> {
+    let files = collect_image_files(&args.input, &WalkOptions::from_args(args), &ProgressBar::hidden())?;
+    for path in &files {
+        println!("{}", path.display());
+    }
+    eprintln!("{} images matched", files.len().to_string().bright_green());
+    Ok(())
+}
+
+pub(crate) fn print_files_found(count: usize) {
+    println!("Found {} images", count.to_string().bright_green());
+}
+
+/// Counts duplicates and, for `--dedupe link`, gives each one the outputs of
+/// its first occurrence under its own planned name. Each duplicate is recorded
+/// like any other file, so it reaches `--jsonl` and `--progress-json` too.
+pub(crate) fn record_duplicates(stats: &mut CompressionStats, duplicates: Vec<Duplicate>, mode: DedupeMode, output_dir: &Path, args: &Args) {
+    stats.duplicates = duplicates.len();
+
+    for duplicate in duplicates {
+        let originals: Vec<FileResult> = stats.file_results.iter()
+            .filter(|result| result.source_path == duplicate.original.input)
+            .cloned()
+            .collect();
+        // The first occurrence failed or was skipped, so there is nothing to link
+        if matches!(mode, DedupeMode::Skip) || originals.is_empty() {
+            stats.record(FileOutcome::Skipped(duplicate.job.input), args.progress_json);
+            continue;
+        }
+
+        let mut results = Vec::with_capacity(originals.len());
+        for original in originals {
+            let name = output_filename(&original.output_path);
+            let suffix = name.strip_prefix(duplicate.original.stem.as_str()).unwrap_or(&name);
+            let linked = args.prepare_output_dir(&duplicate.job, output_dir).and_then(|dir| {
+                let output_path = dir.join(format!("{}{}", duplicate.job.stem, suffix));
+                is_unchanged_source(&output_path, &duplicate.job.input, &[])?;
+                link_or_copy(&original.output_path, &output_path)?;
+                Ok(output_path)
+            });
+            match linked {
+                Ok(output_path) => {
+                    let filename = if original.variant.is_some() {
+                        output_filename(&output_path)
+                    } else {
+                        output_filename(&duplicate.job.input)
+                    };
+                    let relative_path = args.full_paths
+                        .then(|| relative_display_path(&duplicate.job.input, args.display_roots(), &filename));
+                    results.push(FileResult {
+                        filename,
+                        source_path: duplicate.job.input.clone(),
+                        relative_path,
+                        output_path,
+                        ..original
+                    });
+                }
+                Err(e) => {
+                    let error = FileError {
+                        path: duplicate.job.input.clone(),
+                        category: ErrorCategory::classify(&e),
+                        message: describe_error(&e),
+                    };
+                    stats.record(FileOutcome::Failed(error), args.progress_json);
+                }
+            }
+        }
+        if !results.is_empty() {
+            stats.record(FileOutcome::Completed(results), args.progress_json);
+        }
+    }
+}
+
+pub(crate) fn process_files_parallel(
+    jobs: &[Job], 
+    duplicates: Vec<Duplicate>,
+    output_dir: &Path, 
+    args: &Args
+) -> Result<CompressionStats> {
+    let total = jobs.len() + duplicates.len();
+    let pb = if args.progress_json {
+        ProgressEvent::Started { total: Some(total) }.emit();
+        ProgressBar::hidden()
+    } else {
+        create_progress_bar(total)
+    };
+    let stats = Arc::new(Mutex::new(CompressionStats::new()));
+    let pb_arc = Arc::new(pb);
+
+    init_thread_pool();
+
+    let batch = BatchState::new(args);
+
+    let process_file = |job: &Job| {
+        let outcome = process_job(job, output_dir, args, &batch, &pb_arc);
+        if let Ok(mut stats_guard) = stats.lock() {
+            stats_guard.record(outcome, args.progress_json);
+        }
+        pb_arc.inc(1);
+    };
+
+    if args.interactive {
+        // Prompts need a stable order and exclusive use of the terminal
+        jobs.iter().for_each(process_file);
+    } else {
+        // Process files in parallel with optimized chunking for ultra-fast performance
+        jobs.par_iter().for_each(process_file);
+    }
+
+    let mut stats = Arc::try_unwrap(stats)
+        .map_err(|_| anyhow::anyhow!("Failed to unwrap stats"))?
+        .into_inner()
+        .map_err(|_| anyhow::anyhow!("Failed to get stats from mutex"))?;
+
+    // Once every first occurrence is done, so there is something to link to
+    if let Some(mode) = args.dedupe {
+        pb_arc.inc(duplicates.len() as u64);
+        record_duplicates(&mut stats, duplicates, mode, output_dir, args);
+    }
+
+    pb_arc.finish_with_message("Compression complete");
+
+    if args.progress_json {
+        stats.emit_finished();
+    }
+
+    Ok(stats)
+}
+
+/// `--stream`: tallies outcomes on this thread while [`compress_stream`]
+/// walks and compresses on the worker pool, so the first files finish while
+/// the rest of the tree is still being read.
+pub(crate) fn process_files_streaming(output_dir: &Path, args: &Args) -> CompressionStats {
+    let pb = if args.progress_json {
+        ProgressEvent::Started { total: None }.emit();
+        ProgressBar::hidden()
+    } else {
+        create_spinner()
+    };
+
+    let mut stats = CompressionStats::new();
+    for outcome in spawn_stream(output_dir.to_path_buf(), args, pb.clone()) {
+        stats.record(outcome, args.progress_json);
+        pb.inc(1);
+    }
+
+    pb.finish_with_message("Compression complete");
+
+    if args.progress_json {
+        stats.emit_finished();
+    }
+
+    stats
+}
+
+/// Walks `args.input` and compresses files as they turn up, sending each
+/// outcome as soon as it is known, so results arrive while the rest of the
+/// tree is still being read. The channel closes once every file is sent.
+pub fn compress_stream(args: &Args) -> Result<mpsc::Receiver<FileOutcome>> {
+    validate_args(args)?;
+    let output_dir = prepare_output_root(args)?;
+    Ok(spawn_stream(output_dir, args, ProgressBar::hidden()))
+}
+
+/// Starts [`stream_outcomes`] on its own thread, reporting files on `pb`.
+pub(crate) fn spawn_stream(output_dir: PathBuf, args: &Args, pb: ProgressBar) -> mpsc::Receiver<FileOutcome> {
+    init_thread_pool();
+
+    let (tx, rx) = mpsc::channel();
+    let args = args.clone();
+    std::thread::spawn(move || stream_outcomes(&output_dir, &args, &pb, tx));
+    rx
+}
+
+/// Compresses files in walk order on the worker pool, sending each outcome
+/// on `tx`. Returns once the walk is exhausted and every file has been sent.
+pub(crate) fn stream_outcomes(output_dir: &Path, args: &Args, pb: &ProgressBar, tx: mpsc::Sender<FileOutcome>) {
+    let batch = BatchState::new(args);
+    let mut claims = StemClaims::default();
+    let mut seen = HashSet::new();
+    let walk = WalkOptions::from_args(args);
+
+    args.input.iter()
+        .flat_map(|input| walk_image_files(input, &walk))
+        .filter(|path| seen.insert(file_identity(path)))
+        // Claimed on the walking thread, so renames follow walk order
+        .map(|input| {
+            let dir = args.collision_dir(&input);
+            claims.claim(input, args.on_collision, dir).map(|job| job.with_suffix(args))
+        })
+        .par_bridge()
+        .for_each_with(tx, |tx, job| {
+            let outcome = match job {
+                Ok(job) => process_job(&job, output_dir, args, &batch, pb),
+                Err(error) => FileOutcome::Failed(error),
+            };
+            // A closed receiver means nobody wants the remaining results
+            let _ = tx.send(outcome);
+        });
+}
+
+pub(crate) fn init_thread_pool() {
+    // Configure rayon for maximum performance
+    let _ = rayon::ThreadPoolBuilder::new()
+        .num_threads(num_cpus::get().max(1))
+        .build_global(); // Ignore if already initialized
+}
+
+/// State shared by every worker in one run.
+pub(crate) struct BatchState {
+    pub(crate) throttle: Option<Throttle>,
+    /// Bytes written so far; files already in flight may carry the total past the budget
+    pub(crate) written: AtomicU64,
+}
+
+impl BatchState {
+    pub(crate) fn new(args: &Args) -> Self {
+        Self { throttle: args.max_throughput.map(Throttle::new), written: AtomicU64::new(0) }
+    }
+}
+
+/// Runs one job through the resume, budget and throttle checks and then the
+/// encoder, without touching the shared stats.
+pub(crate) fn process_job(job: &Job, output_dir: &Path, args: &Args, batch: &BatchState, pb: &ProgressBar) -> FileOutcome {
+    let file_path = &job.input;
+    let output_dir = &match args.prepare_output_dir(job, output_dir) {
+        Ok(dir) => dir,
+        Err(e) => {
+            return FileOutcome::Failed(FileError {
+                path: file_path.clone(),
+                category: ErrorCategory::classify(&e),
+                message: describe_error(&e),
+            });
+        }
+    };
+    let filename = file_path
+        .file_name()
+        .map_or_else(|| "unknown".to_string(), |n| n.to_string_lossy().to_string());
+
+    pb.set_message(filename);
+
+    if args.total_budget.is_some_and(|budget| batch.written.load(Ordering::Relaxed) >= budget) {
+        return FileOutcome::OverBudget(file_path.clone());
+    }
+
+    if args.resume && has_fresh_output(job, output_dir, args.format_for(&job.input)) {
+        return FileOutcome::Skipped(file_path.clone());
+    }
+
+    if let Some(throttle) = &batch.throttle {
+        throttle.acquire(fs::metadata(file_path).map_or(0, |m| m.len()));
+    }
+
+    let started = Instant::now();
+    let result = if args.interactive {
+        compress_image_interactive(job, output_dir, args, pb)
+            .map(|compressed| compressed.into_iter().collect())
+    } else {
+        // Force compression - no skipping allowed
+        compress_image_force(job, output_dir, args)
+    };
+
+    match result {
+        Ok(outputs) if !outputs.is_empty() => {
+            let duration = started.elapsed();
+            batch.written.fetch_add(outputs.iter().map(|c| c.compressed_size).sum(), Ordering::Relaxed);
+            let results = outputs.into_iter()
+                .map(|compressed| {
+                    // Formats chosen for compatibility are expected to grow
+                    if compressed.compressed_size > compressed.original_size
+                        && !args.format_for(file_path).expects_growth()
+                    {
+                        let message = format!(
+                            "{} grew from {} to {}",
+                            compressed.output_path.display(),
+                            format_size(compressed.original_size, DECIMAL),
+                            format_size(compressed.compressed_size, DECIMAL)
+                        );
+                        pb.suspend(|| print_warning(&message, args.progress_json));
+                    }
+                    if args.verbose
+                        && let Some(quality) = compressed.quality
+                    {
+                        pb.suspend(|| println!("{}: quality {}", compressed.output_path.display(), quality));
+                    }
+                    if args.verbose && matches!(args.format_for(file_path), OutputFormat::Auto) {
+                        let chosen = compressed.output_path.extension().unwrap_or_default().to_string_lossy();
+                        pb.suspend(|| println!("{}: auto format {}", compressed.output_path.display(), chosen));
+                    }
+                    file_result(job, compressed, duration, args)
+                })
+                .collect();
+            FileOutcome::Completed(results)
+        }
+        Ok(_) => FileOutcome::Skipped(file_path.clone()),
+        Err(e) => FileOutcome::Failed(FileError {
+            path: file_path.clone(),
+            category: ErrorCategory::classify(&e),
+            message: describe_error(&e),
+        }),
+    }
+}
+
+/// The `--jsonl` file, opened once before any work starts.
+pub(crate) static RESULT_LOG: OnceLock<Mutex<fs::File>> = OnceLock::new();
+
+/// Appends `result` as one line; each line goes out in a single write, so the
+/// log holds every file recorded before a crash.
+pub(crate) fn log_result(log: &Mutex<fs::File>, result: &FileResult, progress_json: bool) {
+    use std::io::Write;
+
+    let entry = ResultLogEntry {
+        source: result.source_path.display().to_string(),
+        path: result.relative_path.as_ref().map(|path| path.display().to_string()),
+        output: result.output_path.display().to_string(),
+        original_size: result.original_size,
+        compressed_size: result.compressed_size,
+        duration_ms: result.duration.as_millis(),
+        quality: result.quality,
+        sha256: result.sha256.as_deref(),
+    };
+    let Ok(mut line) = serde_json::to_string(&entry) else {
+        return;
+    };
+    line.push('\n');
+    let mut file = log.lock().unwrap_or_else(PoisonError::into_inner);
+    if let Err(e) = file.write_all(line.as_bytes()).and_then(|()| file.flush()) {
+        print_warning(&format!("--jsonl could not be written: {}", e), progress_json);
+    }
+}
+
+/// The result row for one output of `job`; `--widths` outputs are listed
+/// under their own filename.
+pub(crate) fn file_result(job: &Job, compressed: Compressed, duration: Duration, args: &Args) -> FileResult {
+    let filename = if compressed.variant.is_some() {
+        output_filename(&compressed.output_path)
+    } else {
+        job.input
+            .file_name()
+            .map_or_else(|| "unknown".to_string(), |n| n.to_string_lossy().to_string())
+    };
+    let relative_path = args.full_paths.then(|| relative_display_path(&job.input, args.display_roots(), &filename));
+    create_file_result(filename, job.input.clone(), relative_path, compressed, duration)
+}
+
+pub(crate) fn create_file_result(
+    filename: String,
+    source_path: PathBuf,
+    relative_path: Option<PathBuf>,
+    compressed: Compressed,
+    duration: Duration,
+) -> FileResult {
+    FileResult {
+        filename,
+        original_size: compressed.original_size,
+        compressed_size: compressed.compressed_size,
+        source_path,
+        relative_path,
+        output_path: compressed.output_path,
+        sha256: compressed.sha256,
+        variant: compressed.variant,
+        duration,
+        dhash: compressed.dhash,
+        orientation: compressed.orientation,
+        quality: compressed.quality,
+    }
+}
+
+// New function that forces compression of ALL images - no skipping
+pub(crate) fn compress_image_force(job: &Job, output_dir: &Path, args: &Args) -> Result<Vec<Compressed>> {
+    compress_job_outputs(job, output_dir, &OutputSpec::for_job(&job.input, args), args)
+}
+
+/// Compresses `input` into every output in `specs`, decoding it only once.
+/// Everything but the outputs themselves comes from `args`, and outputs land
+/// where the command line would write them. Outputs that `--skip-existing`
+/// or the grow-guard leave alone are missing from the result.
+pub fn compress_image_multi(input: &Path, specs: &[OutputSpec], args: &Args) -> Result<Vec<FileResult>> {
+    validate_args(args)?;
+    let job = plan_jobs(&[input.to_path_buf()], args.on_collision, |_| None)?
+        .remove(0)
+        .with_suffix(args);
+    let output_dir = args.prepare_output_dir(&job, &prepare_output_root(args)?)?;
+
+    let started = Instant::now();
+    let outputs = compress_job_outputs(&job, &output_dir, specs, args)?;
+    let duration = started.elapsed();
+    Ok(outputs.into_iter().map(|compressed| file_result(&job, compressed, duration, args)).collect())
+}
+
+/// Decodes `job`'s source exactly once and writes one output per spec.
+pub(crate) fn compress_job_outputs(job: &Job, output_dir: &Path, specs: &[OutputSpec], args: &Args) -> Result<Vec<Compressed>> {
+    let encoded = with_retry(args, || {
+        let specs = specs.to_vec();
+        encode_with_deadline(job, output_dir, args, move |job, output_dir, args| {
+            encode_outputs(job, output_dir, &specs, args)
+        })
+    })?;
+
+    encoded.into_iter()
+        .filter(|encoded| !keeps_existing(encoded, args))
+        .map(|encoded| write_guarded(encoded, &job.input, args))
+        .collect()
+}
+
+/// Runs `op` until it succeeds, fails with anything but a transient I/O
+/// error, or `--retry` attempts are used up, backing off between attempts.
+pub(crate) fn with_retry<T>(args: &Args, mut op: impl FnMut() -> Result<T>) -> Result<T> {
+    let mut delay = Duration::from_millis(args.retry_delay);
+    let mut attempt = 0;
+    loop {
+        match op() {
+            Err(e) if attempt < args.retry && is_transient(&e) => {
+                std::thread::sleep(delay);
+                delay = delay.saturating_mul(2);
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// I/O errors that could go away on their own. A short read or malformed
+/// data says something about the file itself and fails the same way again.
+pub(crate) fn is_transient(error: &anyhow::Error) -> bool {
+    use std::io::ErrorKind;
+
+    if ErrorCategory::classify(error) != ErrorCategory::Io {
+        return false;
+    }
+    let io_error = error.chain().find_map(|cause| match cause.downcast_ref::<image::ImageError>() {
+        Some(image::ImageError::IoError(e)) => Some(e),
+        _ => cause.downcast_ref::<std::io::Error>(),
+    });
+    !io_error.is_some_and(|e| matches!(e.kind(), ErrorKind::UnexpectedEof | ErrorKind::InvalidData))
+}
+
+pub(crate) fn encode_with_deadline<T, F>(job: &Job, output_dir: &Path, args: &Args, encode: F) -> Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce(&Job, &Path, &Args) -> Result<T> + Send + 'static,
+{
+    use std::sync::mpsc::{self, RecvTimeoutError};
+
+    let Some(timeout) = args.timeout.map(Duration::from_secs) else {
+        return encode(job, output_dir, args);
+    };
+
+    // Decoding can't be interrupted, so run it on a detached thread and stop
+    // waiting at the deadline. Only the encode happens there; the write stays
+    // here, so an abandoned worker can never touch the output afterwards.
+    let (tx, rx) = mpsc::channel();
+    let (worker_job, output, worker_args) = (job.clone(), output_dir.to_path_buf(), args.clone());
+    std::thread::Builder::new()
+        .name("pixelsqueeze-encode".to_string())
+        .spawn(move || {
+            let _ = tx.send(encode(&worker_job, &output, &worker_args));
+        })
+        .with_context(|| "Failed to spawn encode thread")?;
+
+    match rx.recv_timeout(timeout) {
+        Ok(result) => result,
+        Err(RecvTimeoutError::Timeout) => Err(TimedOut(timeout).into()),
+        Err(RecvTimeoutError::Disconnected) => anyhow::bail!("Encode thread panicked"),
+    }
+}
+
+/// One output to produce from a decoded source.
+#[derive(Clone)]
+pub struct OutputSpec {
+    pub format: OutputFormat,
+    /// A responsive `--widths` output at this width; `None` is the regular
+    /// output, resized only by `--max-width`/`--max-height`/`--max-pixels`
+    pub width: Option<u32>,
+}
+
+impl OutputSpec {
+    /// What `args` asks for from `input`: one output, or one per `--widths` entry.
+    pub fn for_job(input: &Path, args: &Args) -> Vec<Self> {
+        let format = args.format_for(input).clone();
+        if args.widths.is_empty() {
+            return vec![Self { format, width: None }];
+        }
+        args.widths.iter()
+            .map(|&width| Self { format: format.clone(), width: Some(width) })
+            .collect()
+    }
+}
+
+pub(crate) fn encode_image(job: &Job, output_dir: &Path, args: &Args) -> Result<EncodedImage> {
+    let spec = OutputSpec { format: args.format_for(&job.input).clone(), width: None };
+    let mut encoded = encode_outputs(job, output_dir, &[spec], args)?;
+    Ok(encoded.remove(0))
+}
+
+/// Decodes the source once and encodes every spec from that one image.
+pub(crate) fn encode_outputs(job: &Job, output_dir: &Path, specs: &[OutputSpec], args: &Args) -> Result<Vec<EncodedImage>> {
+    use image::imageops::FilterType;
+
+    let source = timed(Stage::Decode, || decode_source(&job.input, args))?;
+    let full_width = source.img.width();
+    let (regular, sized): (Vec<&OutputSpec>, Vec<&OutputSpec>) = specs.iter().partition(|spec| spec.width.is_none());
+
+    let mut outputs = Vec::with_capacity(specs.len());
+    if let Some(spec) = regular.first() {
+        let img = timed(Stage::Resize, || resize_image(&source.img, args));
+        outputs.push(timed(Stage::Encode, || encode_output(job, output_dir, &source, &img, spec, args))?);
+    }
+
+    // Never upscale; a source narrower than every width gets one output at its own width
+    let mut widths: Vec<(u32, &OutputSpec)> = sized.iter()
+        .filter_map(|spec| spec.width.filter(|&w| w <= full_width).map(|w| (w, *spec)))
+        .collect();
+    if widths.is_empty()
+        && let Some(spec) = sized.first()
+    {
+        widths.push((full_width, spec));
+    }
+    widths.sort_unstable_by_key(|&(width, _)| width);
+    widths.dedup_by_key(|&mut (width, _)| width);
+
+    for (width, spec) in widths {
+        let resized = if width == full_width {
+            Cow::Borrowed(&source.img)
+        } else {
+            Cow::Owned(timed(Stage::Resize, || source.img.resize(width, u32::MAX, FilterType::Lanczos3)))
+        };
+        outputs.push(timed(Stage::Encode, || encode_output(job, output_dir, &source, &resized, spec, args))?);
+    }
+    Ok(outputs)
+}
+
+pub(crate) fn encode_output(
+    job: &Job,
+    output_dir: &Path,
+    source: &DecodedSource,
+    img: &image::DynamicImage,
+    spec: &OutputSpec,
+    args: &Args,
+) -> Result<EncodedImage> {
+    let input_path = job.input.as_path();
+    let original_size = source.original_size;
+    let img = match args.sharpen {
+        Some(sigma) => &img.unsharpen(sigma, args.sharpen_threshold),
+        None => img,
+    };
+
+    // Smart compression based on input and output formats; widths share the
+    // quality judged on the full-size decode
+    let quality = args.quality_for(&source.img);
+    let (format, data) = encode_resolved(img, &spec.format, quality, input_path, &args.encoder)?;
+    let quality = args.auto_quality.and(quality);
+
+    let mut encoded = if spec.width.is_some() {
+        let output_path = output_dir.join(format!("{}-{}w.{}", job.stem, img.width(), format.extension()));
+        let variant = Some(Variant { width: img.width(), height: img.height() });
+        let data = embed_source_metadata(data, &source.metadata);
+        EncodedImage { original_size, output_path, data, variant, dhash: source.dhash, rejected_size: None, quality, orientation: None }
+    } else {
+        let output_path = output_dir.join(create_output_filename(&job.stem, format));
+        let (data, stepped) = step_down_quality(img, &format.into(), quality, data, original_size, input_path, args)?;
+        let quality = stepped.or(quality);
+        let data = embed_source_metadata(data, &source.metadata);
+        let compressed_size = data.len() as u64;
+
+        // If the compressed file is more than 50% larger (any larger with
+        // --no-grow), use original copy instead
+        let limit = if args.no_grow { original_size } else { original_size + (original_size / 2) };
+        if !spec.format.expects_growth() && compressed_size > limit {
+            EncodedImage {
+                rejected_size: Some(compressed_size),
+                dhash: source.dhash,
+                quality,
+                ..original_copy(job, output_dir, original_size)?
+            }
+        } else {
+            EncodedImage {
+                original_size,
+                output_path,
+                data,
+                variant: None,
+                dhash: source.dhash,
+                rejected_size: None,
+                quality,
+                orientation: None,
+            }
+        }
+    };
+    encoded.orientation = source.orientation;
+
+    let encoded = embed_icc_profile(strip_encoded_chunks(encoded, args), source.icc.as_deref());
+    Ok(stamp_density(encoded, args))
+}
+
+/// `--quality-steps`: re-encodes `step` lower at a time, down to
+/// `--quality-floor`, while the output is not smaller than the source.
+/// Returns the last encode and, if any retry ran, the quality it used.
+pub(crate) fn step_down_quality(
+    img: &image::DynamicImage,
+    format: &OutputFormat,
+    quality: Option<f32>,
+    mut data: Vec<u8>,
+    original_size: u64,
+    input_path: &Path,
+    args: &Args,
+) -> Result<(Vec<u8>, Option<f32>)> {
+    let Some(step) = args.quality_steps else {
+        return Ok((data, None));
+    };
+    if args.encoder.lossless || !matches!(format, OutputFormat::Jpeg | OutputFormat::Webp) {
+        return Ok((data, None));
+    }
+    let mut quality = quality.unwrap_or_else(|| format.default_quality());
+    let mut stepped = None;
+    while data.len() as u64 >= original_size && quality > args.quality_floor {
+        quality = (quality - step).max(args.quality_floor);
+        data = compress_with_smart_settings(img, format, quality, input_path, &args.encoder)?;
+        stepped = Some(quality);
+    }
+    Ok((data, stepped))
+}
+
+pub(crate) fn compress_image_interactive(
+    job: &Job,
+    output_dir: &Path,
+    args: &Args,
+    pb: &ProgressBar,
+) -> Result<Option<Compressed>> {
+    let encoded = encode_with_deadline(job, output_dir, args, encode_image)?;
+    if keeps_existing(&encoded, args) {
+        return Ok(None);
+    }
+
+    let original = format_size(encoded.original_size, DECIMAL);
+    let projected = format_size(encoded.data.len() as u64, DECIMAL);
+    let prompt = format!(
+        "{}: {} → {}  [k]eep / [s]kip / use [o]riginal (default keep): ",
+        job.input.display(),
+        original.bright_cyan(),
+        projected.bright_cyan()
+    );
+
+    let choice = pb.suspend(|| -> Result<String> {
+        use std::io::Write;
+
+        print!("{}", prompt);
+        std::io::stdout().flush()?;
+        let mut line = String::new();
+        // Treat end of input as a skip so nothing gets written unattended
+        if std::io::stdin().read_line(&mut line)? == 0 {
+            return Ok("s".to_string());
+        }
+        Ok(line.trim().to_lowercase())
+    })?;
+
+    match choice.as_str() {
+        "" | "k" | "keep" => write_guarded(encoded, &job.input, args).map(Some),
+        "o" | "original" => {
+            let copy = original_copy(job, output_dir, encoded.original_size)?;
+            timed(Stage::Write, || write_encoded(copy, &job.input, args)).map(Some)
+        }
+        _ => Ok(None),
+    }
+}
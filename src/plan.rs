@@ -0,0 +1,435 @@
+//! Finding input files and planning the output each one is written to.
+
+use anyhow::{Context, Result};
+use indicatif::ProgressBar;
+use rayon::prelude::*;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fs,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+use walkdir::WalkDir;
+
+use crate::cli::{Args, CollisionPolicy, ConcreteFormat, OutputFormat};
+use crate::error::{ErrorCategory, FileError};
+use crate::limits::{READ_SLOTS, ReadSlots};
+
+/// One input file and the stem its outputs are named after.
+#[derive(Debug, Clone)]
+pub(crate) struct Job {
+    pub(crate) input: PathBuf,
+    pub(crate) stem: String,
+}
+
+impl Job {
+    /// Appends `--suffix` once collisions are settled; it is the same for every
+    /// output, so it cannot introduce new ones.
+    pub(crate) fn with_suffix(mut self, args: &Args) -> Self {
+        if let Some(suffix) = &args.suffix {
+            self.stem.push_str(suffix);
+        }
+        self
+    }
+}
+
+/// A job whose source bytes match an earlier job's.
+pub(crate) struct Duplicate {
+    pub(crate) job: Job,
+    pub(crate) original: Job,
+}
+
+/// Names outputs `0001`, `0002`, ... from the sorted input list, so numbering
+/// is independent of the order parallel workers finish in.
+pub(crate) fn number_jobs(files: &[PathBuf], start: u64) -> Result<Vec<Job>> {
+    let last = start
+        .checked_add(files.len().saturating_sub(1) as u64)
+        .context("--number start is too large for this many files")?;
+    let width = last.to_string().len();
+
+    Ok(files.iter()
+        .zip(start..)
+        .map(|(input, n)| Job { input: input.clone(), stem: format!("{:0width$}", n, width = width) })
+        .collect())
+}
+
+/// Assigns every input an output stem, detecting inputs that would clash
+/// (e.g. `a.jpg` and `a.jpeg` both becoming `a.webp`) before anything is written.
+pub(crate) fn plan_jobs(
+    files: &[PathBuf],
+    policy: CollisionPolicy,
+    collision_dir: impl Fn(&Path) -> Option<PathBuf>,
+) -> Result<Vec<Job>> {
+    let mut stems = Vec::with_capacity(files.len());
+    for path in files {
+        let stem = path.file_stem()
+            .and_then(|s| s.to_str())
+            .ok_or_else(|| anyhow::anyhow!("Invalid filename: {}", path.display()))?;
+        stems.push(stem.to_string());
+    }
+    let dirs: Vec<Option<PathBuf>> = files.iter().map(|path| collision_dir(path)).collect();
+
+    // Compare case-insensitively so the plan also holds on macOS and Windows
+    let mut claimed: BTreeMap<String, Vec<&Path>> = BTreeMap::new();
+    for ((path, stem), dir) in files.iter().zip(&stems).zip(&dirs) {
+        claimed.entry(collision_key(dir.as_deref(), stem)).or_default().push(path);
+    }
+
+    let conflicts: Vec<_> = claimed.values().filter(|paths| paths.len() > 1).collect();
+    if conflicts.is_empty() {
+        return Ok(files.iter().cloned().zip(stems).map(|(input, stem)| Job { input, stem }).collect());
+    }
+
+    if matches!(policy, CollisionPolicy::Error) {
+        let listing = conflicts.iter()
+            .map(|paths| {
+                let names = paths.iter().map(|p| p.display().to_string()).collect::<Vec<_>>();
+                format!("  {}", names.join(", "))
+            })
+            .collect::<Vec<_>>()
+            .join("\n");
+        anyhow::bail!(
+            "These inputs would overwrite each other's output:\n{}\nRename them or pass --on-collision rename",
+            listing
+        );
+    }
+
+    let mut taken: std::collections::HashSet<String> = claimed.keys().cloned().collect();
+    let mut seen: std::collections::HashSet<String> = std::collections::HashSet::new();
+    let mut jobs = Vec::with_capacity(files.len());
+    for ((input, stem), dir) in files.iter().zip(stems).zip(&dirs) {
+        let dir = dir.as_deref();
+        let mut unique = stem.clone();
+        if !seen.insert(collision_key(dir, &stem)) {
+            // Later duplicates get the first free numeric suffix
+            let mut n = 2;
+            while taken.contains(&collision_key(dir, &format!("{}-{}", stem, n))) {
+                n += 1;
+            }
+            unique = format!("{}-{}", stem, n);
+            taken.insert(collision_key(dir, &unique));
+        }
+        jobs.push(Job { input: input.clone(), stem: unique });
+    }
+    Ok(jobs)
+}
+
+/// What two outputs must share to overwrite each other: the stem, compared
+/// case-insensitively, and the directory they land in when outputs are split
+/// across directories.
+pub(crate) fn collision_key(dir: Option<&Path>, stem: &str) -> String {
+    match dir {
+        Some(dir) => dir.join(stem).to_string_lossy().to_lowercase(),
+        None => stem.to_lowercase(),
+    }
+}
+
+/// Hashes every source and keeps the first job for each distinct content;
+/// unreadable files are kept so their error is reported normally.
+pub(crate) fn split_duplicates(jobs: Vec<Job>) -> (Vec<Job>, Vec<Duplicate>) {
+    let hashes: Vec<Option<[u8; 32]>> = jobs.par_iter()
+        .map(|job| {
+            let _slot = READ_SLOTS.get().map(ReadSlots::acquire);
+            fs::read(&job.input).ok().map(|data| Sha256::digest(&data).into())
+        })
+        .collect();
+
+    let mut first_seen: HashMap<[u8; 32], Job> = HashMap::new();
+    let mut unique = Vec::with_capacity(jobs.len());
+    let mut duplicates = Vec::new();
+    for (job, hash) in jobs.into_iter().zip(hashes) {
+        match hash {
+            Some(hash) => match first_seen.get(&hash) {
+                Some(original) => duplicates.push(Duplicate { job, original: original.clone() }),
+                None => {
+                    first_seen.insert(hash, job.clone());
+                    unique.push(job);
+                }
+            },
+            None => unique.push(job),
+        }
+    }
+    (unique, duplicates)
+}
+
+/// Output stems handed out so far under `--stream`, where collisions are
+/// resolved as files turn up instead of being planned from the full list.
+#[derive(Default)]
+pub(crate) struct StemClaims {
+    /// Collision key of each stem to the input that claimed it
+    pub(crate) taken: HashMap<String, PathBuf>,
+}
+
+impl StemClaims {
+    pub(crate) fn claim(&mut self, input: PathBuf, policy: CollisionPolicy, dir: Option<PathBuf>) -> Result<Job, FileError> {
+        let dir = dir.as_deref();
+        let Some(stem) = input.file_stem().and_then(|s| s.to_str()).map(str::to_string) else {
+            let message = format!("Invalid filename: {}", input.display());
+            return Err(FileError { path: input, category: ErrorCategory::Other, message });
+        };
+
+        let Some(first) = self.taken.get(&collision_key(dir, &stem)) else {
+            self.taken.insert(collision_key(dir, &stem), input.clone());
+            return Ok(Job { input, stem });
+        };
+
+        if matches!(policy, CollisionPolicy::Error) {
+            let message = format!(
+                "Would overwrite the output of {}; rename it or pass --on-collision rename",
+                first.display()
+            );
+            return Err(FileError { path: input, category: ErrorCategory::Other, message });
+        }
+
+        let mut n = 2;
+        while self.taken.contains_key(&collision_key(dir, &format!("{}-{}", stem, n))) {
+            n += 1;
+        }
+        let unique = format!("{}-{}", stem, n);
+        self.taken.insert(collision_key(dir, &unique), input.clone());
+        Ok(Job { input, stem: unique })
+    }
+}
+
+/// The grow-guard copy keeps the source extension under the planned stem.
+pub(crate) fn original_copy_filename(job: &Job) -> String {
+    match job.input.extension() {
+        Some(ext) => format!("{}.{}", job.stem, ext.to_string_lossy()),
+        None => job.stem.clone(),
+    }
+}
+
+pub(crate) fn has_fresh_output(job: &Job, output_dir: &Path, format: &OutputFormat) -> bool {
+    let Ok(source_modified) = fs::metadata(&job.input).and_then(|m| m.modified()) else {
+        return false;
+    };
+
+    // Under --output-same-dir a candidate can be the source itself, which is never stale
+    let source_identity = file_identity(&job.input);
+    let is_fresh = |candidate: &Path, expected: Option<image::ImageFormat>| {
+        if file_identity(candidate) == source_identity {
+            return false;
+        }
+        let Ok(metadata) = fs::metadata(candidate) else {
+            return false;
+        };
+        if metadata.len() == 0 || metadata.modified().map_or(true, |m| m < source_modified) {
+            return false;
+        }
+        // Validate the header so a half-written file from a crash is redone
+        let mut header = [0u8; 16];
+        let Ok(read) = fs::File::open(candidate).and_then(|mut f| std::io::Read::read(&mut f, &mut header)) else {
+            return false;
+        };
+        match (image::guess_format(&header[..read]), expected) {
+            (Ok(actual), Some(expected)) => actual == expected,
+            (Ok(_), None) => true,
+            (Err(_), _) => false,
+        }
+    };
+
+    let has_fresh_encode = format.candidates().iter().any(|candidate| {
+        let name = create_output_filename(&job.stem, *candidate);
+        is_fresh(&output_dir.join(name), Some(candidate.image_format()))
+    });
+
+    // The grow-guard may have kept a copy of the original instead
+    has_fresh_encode || is_fresh(&output_dir.join(original_copy_filename(job)), None)
+}
+
+pub(crate) fn output_filename(path: &Path) -> String {
+    path.file_name()
+        .map_or_else(|| path.display().to_string(), |n| n.to_string_lossy().to_string())
+}
+
+/// Which files under the inputs are picked up.
+pub(crate) struct WalkOptions<'a> {
+    pub(crate) recursive: bool,
+    pub(crate) include_hidden: bool,
+    pub(crate) exclude_dirs: &'a [PathBuf],
+    /// `--since`: skip files last modified at or before this
+    pub(crate) since: Option<SystemTime>,
+}
+
+impl WalkOptions<'_> {
+    pub(crate) fn from_args(args: &Args) -> WalkOptions<'_> {
+        WalkOptions {
+            recursive: args.recursive,
+            include_hidden: args.include_hidden,
+            exclude_dirs: &args.exclude_dir,
+            since: args.since,
+        }
+    }
+}
+
+pub(crate) fn collect_image_files(inputs: &[PathBuf], walk: &WalkOptions, pb: &ProgressBar) -> Result<Vec<PathBuf>> {
+    let mut files: Vec<PathBuf> = inputs.iter()
+        .flat_map(|input| walk_image_files(input, walk))
+        .inspect(|_| pb.inc(1))
+        .collect();
+    pb.finish_and_clear();
+
+    // Stable order keeps collision renames and progress output reproducible
+    files.sort();
+    let mut seen = HashSet::new();
+    files.retain(|path| seen.insert(file_identity(path)));
+    Ok(files)
+}
+
+/// Where `path` sits below whichever of the input roots it was found under.
+pub(crate) fn relative_to_input<'a>(path: &'a Path, roots: &[PathBuf]) -> &'a Path {
+    roots.iter()
+        .find_map(|root| path.strip_prefix(root).ok())
+        .unwrap_or(path)
+}
+
+/// `path` below `root`, comparing canonical paths when the spellings differ
+/// (`./photos` and `photos`).
+pub(crate) fn relative_to_root(path: &Path, root: &Path) -> Option<PathBuf> {
+    path.strip_prefix(root)
+        .ok()
+        .map(Path::to_path_buf)
+        .or_else(|| file_identity(path).strip_prefix(file_identity(root)).ok().map(Path::to_path_buf))
+}
+
+/// `--full-paths` naming: the source's directory below its input root,
+/// joined with the name the results table would otherwise show.
+pub(crate) fn relative_display_path(source: &Path, roots: &[PathBuf], filename: &str) -> PathBuf {
+    roots.iter()
+        .find_map(|root| relative_to_root(source, root))
+        .unwrap_or_else(|| source.to_path_buf())
+        .parent()
+        .map_or_else(|| PathBuf::from(filename), |dir| dir.join(filename))
+}
+
+/// Overlapping inputs (`photos/` and `photos/a.png`) reach the same file
+/// under different spellings; the canonical path tells them apart.
+pub(crate) fn file_identity(path: &Path) -> PathBuf {
+    fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+/// Lazily yields the image files under `input`, sorted by name within each
+/// directory. `input` may also be a single image file.
+pub(crate) fn walk_image_files<'a>(input: &'a Path, walk: &WalkOptions<'a>) -> impl Iterator<Item = PathBuf> + Send + 'a {
+    let WalkOptions { recursive, include_hidden, exclude_dirs, since } = *walk;
+    let walker = if recursive {
+        WalkDir::new(input)
+    } else {
+        WalkDir::new(input).max_depth(1)
+    };
+
+    // The input itself is exempt so `pixelsqueeze .assets` still works;
+    // pruning a hidden directory skips everything beneath it
+    let visible = move |entry: &walkdir::DirEntry| {
+        include_hidden || entry.depth() == 0 || !entry.file_name().to_string_lossy().starts_with('.')
+    };
+    // A bare name matches that directory anywhere; anything longer is a path
+    // from the input (or as spelled on the command line)
+    let excluded = move |entry: &walkdir::DirEntry| {
+        entry.depth() > 0 && entry.file_type().is_dir() && exclude_dirs.iter().any(|pattern| {
+            if pattern.components().count() == 1 {
+                entry.file_name() == pattern.as_os_str()
+            } else {
+                entry.path().strip_prefix(input).is_ok_and(|relative| relative == pattern) || entry.path() == pattern
+            }
+        })
+    };
+
+    walker.sort_by_file_name()
+        .into_iter()
+        .filter_entry(move |entry| visible(entry) && !excluded(entry))
+        .filter_map(|e| e.ok())
+        .map(walkdir::DirEntry::into_path)
+        .filter(|path| path.is_file() && is_image_file(path))
+        .filter(move |path| {
+            since.is_none_or(|cutoff| fs::metadata(path).and_then(|m| m.modified()).is_ok_and(|modified| modified > cutoff))
+        })
+}
+
+pub(crate) fn is_image_file(path: &Path) -> bool {
+    const SUPPORTED_EXTENSIONS: &[&str] = &[
+        "jpg", "jpeg", "png", "webp", "bmp", "tga", "tiff", "gif"
+    ];
+    
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| SUPPORTED_EXTENSIONS.contains(&ext.to_lowercase().as_str()))
+        .unwrap_or(false)
+}
+
+pub(crate) fn create_output_filename(stem: &str, format: ConcreteFormat) -> String {
+    format!("{}.{}", stem, format.extension())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Flags, sorted input files, then the planned stems or `None` for an error.
+    type Case = (&'static [&'static str], &'static [&'static str], Option<&'static [&'static str]>);
+
+    /// The stems `compress` would name each file's outputs after, as planned
+    /// from the sorted input list under `photos`.
+    fn planned(flags: &[&str], files: &[&str]) -> Result<Vec<String>> {
+        let args = Args::try_parse_from(["pixelsqueeze", "photos"].iter().chain(flags)).unwrap();
+        let files: Vec<PathBuf> = files.iter().map(PathBuf::from).collect();
+        let jobs = plan_jobs(&files, args.on_collision, |path| args.collision_dir(path))?;
+        Ok(jobs.into_iter().map(|job| job.with_suffix(&args).stem).collect())
+    }
+
+    #[test]
+    fn plan_jobs_settles_collisions() {
+        const RENAME: &str = "--on-collision=rename";
+        let cases: &[Case] = &[
+            (&[], &["photos/a.jpg", "photos/b.jpg"], Some(&["a", "b"])),
+            (&[], &["photos/a.jpeg", "photos/a.jpg"], None),
+            (&[], &["photos/A.png", "photos/a.jpg"], None),
+            (&[RENAME], &["photos/A.png", "photos/a.jpeg", "photos/a.jpg"], Some(&["A", "a-2", "a-3"])),
+            // An input already named like a rename keeps it; the rename skips past
+            (&[RENAME], &["photos/a-2.png", "photos/a.gif", "photos/a.jpg"], Some(&["a-2", "a", "a-3"])),
+            (&[RENAME], &["photos/a-2.png", "photos/a-3.png", "photos/a.gif", "photos/a.jpg"], Some(&["a-2", "a-3", "a", "a-4"])),
+            // --suffix goes after the rename number and can't cause a clash of its own
+            (&[RENAME, "--suffix=-2"], &["photos/a-2.png", "photos/a.jpg"], Some(&["a-2-2", "a-2"])),
+            (&[RENAME, "--suffix=-min"], &["photos/a.jpg", "photos/a.png"], Some(&["a-min", "a-2-min"])),
+            (&["--suffix=-min"], &["photos/a.jpg", "photos/a.png"], None),
+            // --widths names every output after the stem, so it is planned the same
+            (&["--widths=320,640"], &["photos/a.jpg", "photos/a.png"], None),
+            (&[RENAME, "--widths=320,640"], &["photos/a.jpg", "photos/a.png"], Some(&["a", "a-2"])),
+            // Mirrored directories only clash when they end up in the same one
+            (&["--keep-structure-depth=1"], &["photos/2024/a.jpg", "photos/2025/a.jpg"], Some(&["a", "a"])),
+            (&["--keep-structure-depth=1"], &["photos/2024/feb/a.jpg", "photos/2024/jan/a.jpg"], None),
+            (&["--keep-structure-depth=2"], &["photos/2024/feb/a.jpg", "photos/2024/jan/a.jpg"], Some(&["a", "a"])),
+            (
+                &[RENAME, "--keep-structure-depth=1"],
+                &["photos/2024/feb/a.jpg", "photos/2024/jan/a.jpg", "photos/2025/a.jpg"],
+                Some(&["a", "a-2", "a"]),
+            ),
+            (&["--keep-structure-depth=0"], &["photos/2024/a.jpg", "photos/2025/a.jpg"], None),
+        ];
+
+        for (flags, files, expected) in cases {
+            let planned = planned(flags, files);
+            match expected {
+                Some(stems) => assert_eq!(planned.unwrap(), *stems, "{flags:?} {files:?}"),
+                None => {
+                    let message = planned.expect_err(&format!("{flags:?} {files:?} should collide")).to_string();
+                    assert!(files.iter().all(|file| message.contains(file)), "{message}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn stream_claims_match_planned_stems() {
+        let files = ["photos/a-2.png", "photos/a.gif", "photos/a.jpg", "photos/A.png"];
+        let mut claims = StemClaims::default();
+        let stems: Vec<String> = files.iter()
+            .map(|file| claims.claim(PathBuf::from(file), CollisionPolicy::Rename, None).unwrap().stem)
+            .collect();
+        assert_eq!(stems, ["a-2", "a", "a-3", "A-4"]);
+
+        let error = claims.claim(PathBuf::from("photos/a.webp"), CollisionPolicy::Error, None).unwrap_err();
+        assert!(error.message.contains("photos/a.gif"), "{}", error.message);
+    }
+}
@@ -0,0 +1,170 @@
+//! `--preview`: showing an original next to its compressed output.
+
+use anyhow::{Context, Result};
+use colored::Colorize;
+use std::path::Path;
+
+use crate::cli::PreviewMode;
+use crate::error::describe_error;
+use crate::report::{print_warning, CompressionStats};
+
+/// `--preview`: points at, opens or draws the before and after of the one file.
+pub(crate) fn show_preview(stats: &CompressionStats, mode: PreviewMode, progress_json: bool) {
+    let Some(result) = stats.file_results.first() else {
+        return;
+    };
+
+    println!();
+    println!("Original: {}", result.source_path.display().to_string().bright_cyan());
+    println!("Output:   {}", result.output_path.display().to_string().bright_cyan());
+
+    let outcome = match mode {
+        PreviewMode::Print => Ok(()),
+        PreviewMode::Open => open_in_viewer(&result.source_path).and_then(|()| open_in_viewer(&result.output_path)),
+        #[cfg(feature = "inline-preview")]
+        PreviewMode::Inline => print_inline_preview(&result.source_path, &result.output_path),
+    };
+    if let Err(e) = outcome {
+        print_warning(&format!("--preview: {}", describe_error(&e)), progress_json);
+    }
+}
+
+/// Hands the file to the desktop's default viewer without waiting for it.
+pub(crate) fn open_in_viewer(path: &Path) -> Result<()> {
+    use std::process::{Command, Stdio};
+
+    let mut command = if cfg!(target_os = "macos") {
+        Command::new("open")
+    } else if cfg!(windows) {
+        let mut command = Command::new("cmd");
+        command.args(["/C", "start", ""]);
+        command
+    } else {
+        Command::new("xdg-open")
+    };
+    command.arg(path)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .with_context(|| format!("Failed to open a viewer for {}", path.display()))?;
+    Ok(())
+}
+
+#[cfg(feature = "inline-preview")]
+pub(crate) fn print_inline_preview(original: &Path, output: &Path) -> Result<()> {
+    use std::io::Write;
+
+    let strip = preview_strip(original, output)?;
+    let escape = match std::env::var("TERM_PROGRAM").as_deref() {
+        Ok("iTerm.app" | "WezTerm") => iterm_image(&strip)?,
+        _ => sixel_image(&strip),
+    };
+    let mut stdout = std::io::stdout().lock();
+    stdout.write_all(escape.as_bytes())?;
+    writeln!(stdout)?;
+    Ok(())
+}
+
+/// The original and the output side by side at thumbnail height.
+#[cfg(feature = "inline-preview")]
+pub(crate) fn preview_strip(original: &Path, output: &Path) -> Result<image::RgbImage> {
+    use image::imageops::{self, FilterType};
+
+    const HEIGHT: u32 = 160;
+    const GAP: u32 = 8;
+
+    let thumbnail = |path: &Path| -> Result<image::RgbImage> {
+        let img = image::open(path).with_context(|| format!("Failed to open image: {}", path.display()))?;
+        Ok(img.resize(u32::MAX, HEIGHT, FilterType::Triangle).to_rgb8())
+    };
+    let (before, after) = (thumbnail(original)?, thumbnail(output)?);
+
+    let width = before.width() + GAP + after.width();
+    let mut strip = image::RgbImage::new(width, before.height().max(after.height()));
+    imageops::replace(&mut strip, &before, 0, 0);
+    imageops::replace(&mut strip, &after, i64::from(before.width() + GAP), 0);
+    Ok(strip)
+}
+
+/// iTerm2's inline image escape, carrying the strip as a base64 PNG.
+#[cfg(feature = "inline-preview")]
+pub(crate) fn iterm_image(img: &image::RgbImage) -> Result<String> {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut png = Vec::new();
+    img.write_to(&mut std::io::Cursor::new(&mut png), image::ImageFormat::Png)?;
+
+    let mut encoded = String::with_capacity(png.len().div_ceil(3) * 4);
+    for chunk in png.chunks(3) {
+        let bits = chunk.iter().enumerate().fold(0u32, |bits, (i, &byte)| bits | u32::from(byte) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(char::from(ALPHABET[(bits >> (18 - 6 * i)) as usize & 63]));
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    Ok(format!("\x1b]1337;File=inline=1;size={}:{}\x07", png.len(), encoded))
+}
+
+/// DEC sixel graphics: a 256-color palette, then bands six pixels tall with
+/// one run-length encoded pass per color used in the band.
+#[cfg(feature = "inline-preview")]
+pub(crate) fn sixel_image(img: &image::RgbImage) -> String {
+    use std::fmt::Write;
+
+    let rgba: Vec<u8> = img.pixels().flat_map(|p| [p[0], p[1], p[2], 255]).collect();
+    let quantizer = color_quant::NeuQuant::new(10, 256, &rgba);
+    let indices: Vec<usize> = rgba.chunks_exact(4).map(|px| quantizer.index_of(px)).collect();
+    let (width, height) = (img.width() as usize, img.height() as usize);
+
+    let mut out = format!("\x1bPq\"1;1;{};{}", width, height);
+    for (i, rgb) in quantizer.color_map_rgb().chunks_exact(3).enumerate() {
+        // Sixel color components are percentages
+        let percent = |c: u8| u32::from(c) * 100 / 255;
+        let _ = write!(out, "#{};2;{};{};{}", i, percent(rgb[0]), percent(rgb[1]), percent(rgb[2]));
+    }
+
+    for top in (0..height).step_by(6) {
+        let rows = top..(top + 6).min(height);
+        let mut used = [false; 256];
+        for y in rows.clone() {
+            for &index in &indices[y * width..(y + 1) * width] {
+                used[index] = true;
+            }
+        }
+
+        for color in (0..256).filter(|&c| used[c]) {
+            let _ = write!(out, "#{}", color);
+            let mut run: Option<(char, usize)> = None;
+            for x in 0..width {
+                let bits = rows.clone()
+                    .filter(|&y| indices[y * width + x] == color)
+                    .fold(0u8, |bits, y| bits | 1 << (y - top));
+                let sixel = char::from(63 + bits);
+                run = match run {
+                    Some((previous, count)) if previous == sixel => Some((previous, count + 1)),
+                    _ => {
+                        push_sixel_run(&mut out, run);
+                        Some((sixel, 1))
+                    }
+                };
+            }
+            push_sixel_run(&mut out, run);
+            out.push('$'); // back to the start of the band for the next color
+        }
+        out.push('-');
+    }
+    out.push_str("\x1b\\");
+    out
+}
+
+#[cfg(feature = "inline-preview")]
+pub(crate) fn push_sixel_run(out: &mut String, run: Option<(char, usize)>) {
+    match run {
+        Some((sixel, count)) if count > 3 => out.push_str(&format!("!{}{}", count, sixel)),
+        Some((sixel, count)) => out.extend(std::iter::repeat_n(sixel, count)),
+        None => {}
+    }
+}
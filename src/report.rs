@@ -0,0 +1,541 @@
+//! Summaries of a run: tables, manifests and `--progress-json` events.
+
+use anyhow::{Context, Result};
+use colored::Colorize;
+use comfy_table::Table;
+use humansize::{format_size, DECIMAL};
+use image::metadata::Orientation;
+use indicatif::{ProgressBar, ProgressStyle};
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use crate::cli::{Args, ResultColumn};
+use crate::error::{ErrorCategory, FileError};
+use crate::pipeline::{log_result, FileOutcome, FileResult, RESULT_LOG};
+use crate::plan::{output_filename, relative_to_input};
+use crate::similarity::similar_clusters;
+
+#[derive(Serialize)]
+pub(crate) struct SrcsetEntry {
+    pub(crate) source: String,
+    pub(crate) srcset: String,
+    pub(crate) variants: Vec<SrcsetVariant>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct SrcsetVariant {
+    pub(crate) file: String,
+    pub(crate) width: u32,
+    pub(crate) height: u32,
+    pub(crate) size: u64,
+}
+
+#[derive(Serialize)]
+pub(crate) struct ManifestEntry {
+    pub(crate) output: String,
+    pub(crate) source: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) path: Option<String>,
+    pub(crate) sha256: String,
+    pub(crate) size: u64,
+}
+
+/// One `--jsonl` line, written as soon as the file's output is recorded.
+#[derive(Serialize)]
+pub(crate) struct ResultLogEntry<'a> {
+    pub(crate) source: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) path: Option<String>,
+    pub(crate) output: String,
+    pub(crate) original_size: u64,
+    pub(crate) compressed_size: u64,
+    pub(crate) duration_ms: u128,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) quality: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) sha256: Option<&'a str>,
+}
+
+/// A lifecycle event for `--progress-json`, written as one line of JSON.
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "kebab-case")]
+pub(crate) enum ProgressEvent<'a> {
+    Started {
+        /// Unknown (null) under `--stream`
+        total: Option<usize>,
+    },
+    FileCompleted {
+        source: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        path: Option<String>,
+        output: String,
+        original_size: u64,
+        compressed_size: u64,
+    },
+    FileSkipped {
+        source: String,
+    },
+    FileFailed {
+        source: String,
+        category: &'a str,
+        message: &'a str,
+    },
+    Warning {
+        message: &'a str,
+    },
+    Finished {
+        files_processed: usize,
+        files_skipped: usize,
+        errors: usize,
+        original_size: u64,
+        compressed_size: u64,
+        savings_percent: f64,
+    },
+}
+
+impl ProgressEvent<'_> {
+    /// Stdout stays reserved for the human-readable report.
+    pub(crate) fn emit(&self) {
+        if let Ok(line) = serde_json::to_string(self) {
+            eprintln!("{}", line);
+        }
+    }
+}
+
+/// Prints a warning to stderr, or under `--progress-json` sends it as a
+/// `warning` event so stderr stays one JSON object per line.
+pub(crate) fn print_warning(message: &str, progress_json: bool) {
+    if progress_json {
+        ProgressEvent::Warning { message }.emit();
+    } else {
+        eprintln!("{} {}", "warning:".bright_yellow().bold(), message);
+    }
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct DirSummary {
+    pub(crate) files: usize,
+    pub(crate) original_size: u64,
+    pub(crate) compressed_size: u64,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct CompressionStats {
+    pub(crate) files_processed: usize,
+    pub(crate) files_skipped: usize,
+    /// Files never started because `--total-budget` was reached
+    pub(crate) over_budget: usize,
+    pub(crate) duplicates: usize,
+    /// Outputs larger than their source
+    pub(crate) grown: usize,
+    pub(crate) original_size: u64,
+    pub(crate) compressed_size: u64,
+    pub(crate) errors: Vec<FileError>,
+    pub(crate) file_results: Vec<FileResult>,
+}
+
+impl CompressionStats {
+    pub(crate) const fn new() -> Self {
+        Self {
+            files_processed: 0,
+            files_skipped: 0,
+            over_budget: 0,
+            duplicates: 0,
+            grown: 0,
+            original_size: 0,
+            compressed_size: 0,
+            errors: Vec::new(),
+            file_results: Vec::new(),
+        }
+    }
+
+    pub(crate) fn savings_percent(&self) -> f64 {
+        if self.original_size == 0 {
+            0.0
+        } else {
+            let savings = self.original_size.saturating_sub(self.compressed_size) as f64;
+            (savings / self.original_size as f64) * 100.0
+        }
+    }
+
+    pub(crate) fn add_file_result(&mut self, result: FileResult, progress_json: bool) {
+        self.files_processed += 1;
+        if result.compressed_size > result.original_size {
+            self.grown += 1;
+        }
+        self.original_size += result.original_size;
+        self.compressed_size += result.compressed_size;
+        if let Some(log) = RESULT_LOG.get() {
+            log_result(log, &result, progress_json);
+        }
+        self.file_results.push(result);
+    }
+
+    /// Tallies one file's outcome, mirroring it as a `--progress-json` event.
+    pub(crate) fn record(&mut self, outcome: FileOutcome, progress_json: bool) {
+        match outcome {
+            FileOutcome::Completed(results) => {
+                for result in results {
+                    if progress_json {
+                        ProgressEvent::FileCompleted {
+                            source: result.source_path.display().to_string(),
+                            path: result.relative_path.as_ref().map(|path| path.display().to_string()),
+                            output: result.output_path.display().to_string(),
+                            original_size: result.original_size,
+                            compressed_size: result.compressed_size,
+                        }
+                        .emit();
+                    }
+                    self.add_file_result(result, progress_json);
+                }
+            }
+            FileOutcome::Skipped(source) => {
+                if progress_json {
+                    ProgressEvent::FileSkipped { source: source.display().to_string() }.emit();
+                }
+                self.files_skipped += 1;
+            }
+            FileOutcome::OverBudget(source) => {
+                if progress_json {
+                    ProgressEvent::FileSkipped { source: source.display().to_string() }.emit();
+                }
+                self.over_budget += 1;
+            }
+            FileOutcome::Failed(error) => {
+                if progress_json {
+                    ProgressEvent::FileFailed {
+                        source: error.path.display().to_string(),
+                        category: error.category.label(),
+                        message: &error.message,
+                    }
+                    .emit();
+                }
+                self.errors.push(error);
+            }
+        }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.files_processed + self.files_skipped + self.over_budget + self.errors.len() == 0
+    }
+
+    pub(crate) fn emit_finished(&self) {
+        ProgressEvent::Finished {
+            files_processed: self.files_processed,
+            files_skipped: self.files_skipped,
+            errors: self.errors.len(),
+            original_size: self.original_size,
+            compressed_size: self.compressed_size,
+            savings_percent: self.savings_percent(),
+        }
+        .emit();
+    }
+
+    pub(crate) fn group_by_dir(&self, roots: &[PathBuf]) -> BTreeMap<String, DirSummary> {
+        let mut groups: BTreeMap<String, DirSummary> = BTreeMap::new();
+        for result in &self.file_results {
+            // Bucket by the first component below the input root it came from
+            let relative = relative_to_input(&result.source_path, roots);
+            let key = match relative.parent().and_then(|p| p.components().next()) {
+                Some(component) => component.as_os_str().to_string_lossy().to_string(),
+                None => ".".to_string(),
+            };
+            let summary = groups.entry(key).or_default();
+            summary.files += 1;
+            summary.original_size += result.original_size;
+            summary.compressed_size += result.compressed_size;
+        }
+        groups
+    }
+
+    pub(crate) fn error_counts(&self) -> BTreeMap<ErrorCategory, usize> {
+        let mut counts = BTreeMap::new();
+        for error in &self.errors {
+            *counts.entry(error.category).or_insert(0) += 1;
+        }
+        counts
+    }
+
+}
+
+pub(crate) fn write_srcset_manifest(stats: &CompressionStats, output_dir: &Path) -> Result<()> {
+    let mut by_source: BTreeMap<&Path, Vec<&FileResult>> = BTreeMap::new();
+    for result in stats.file_results.iter().filter(|r| r.variant.is_some()) {
+        by_source.entry(result.source_path.as_path()).or_default().push(result);
+    }
+
+    let entries: Vec<SrcsetEntry> = by_source.into_iter()
+        .map(|(source, mut results)| {
+            results.sort_by_key(|r| r.variant.map(|v| v.width));
+            let variants: Vec<SrcsetVariant> = results.iter()
+                .filter_map(|r| r.variant.map(|variant| SrcsetVariant {
+                    file: output_filename(&r.output_path),
+                    width: variant.width,
+                    height: variant.height,
+                    size: r.compressed_size,
+                }))
+                .collect();
+            let srcset = variants.iter()
+                .map(|v| format!("{} {}w", v.file, v.width))
+                .collect::<Vec<_>>()
+                .join(", ");
+            SrcsetEntry { source: source.display().to_string(), srcset, variants }
+        })
+        .collect();
+
+    let manifest_path = output_dir.join("srcset.json");
+    fs::write(&manifest_path, serde_json::to_string_pretty(&entries)? + "\n")
+        .with_context(|| format!("Failed to write srcset manifest: {}", manifest_path.display()))?;
+
+    println!("Srcset manifest written to {}", manifest_path.display().to_string().bright_cyan());
+    Ok(())
+}
+
+pub(crate) fn write_manifest(stats: &CompressionStats, manifest_path: &Path) -> Result<()> {
+    let mut entries: Vec<ManifestEntry> = stats.file_results.iter()
+        .filter_map(|result| {
+            result.sha256.as_ref().map(|sha256| ManifestEntry {
+                output: output_filename(&result.output_path),
+                source: result.source_path.display().to_string(),
+                path: result.relative_path.as_ref().map(|path| path.display().to_string()),
+                sha256: sha256.clone(),
+                size: result.compressed_size,
+            })
+        })
+        .collect();
+    entries.sort_by(|a, b| a.output.cmp(&b.output));
+
+    let is_text = manifest_path.extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("txt"));
+
+    let contents = if is_text {
+        entries.iter()
+            .map(|e| format!("{}  {}  {}  {}\n", e.sha256, e.size, e.output, e.source))
+            .collect::<String>()
+    } else {
+        serde_json::to_string_pretty(&entries)? + "\n"
+    };
+
+    fs::write(manifest_path, contents)
+        .with_context(|| format!("Failed to write manifest: {}", manifest_path.display()))?;
+
+    println!("Manifest written to {}", manifest_path.display().to_string().bright_cyan());
+    Ok(())
+}
+
+pub(crate) fn print_banner() {
+    println!("{} {}", "PixelSqueeze".bright_white().bold(), env!("CARGO_PKG_VERSION").bright_green());
+}
+
+/// Feedback while a large tree is walked, before the real progress bar exists.
+pub(crate) fn create_scan_spinner() -> ProgressBar {
+    let pb = ProgressBar::new_spinner();
+
+    let style = ProgressStyle::default_spinner()
+        .template("{spinner} scanning… {pos} files found")
+        .expect("Invalid progress bar template");
+
+    pb.set_style(style);
+    pb.enable_steady_tick(Duration::from_millis(100));
+    pb
+}
+
+/// Progress for `--stream`, where the total isn't known up front.
+pub(crate) fn create_spinner() -> ProgressBar {
+    let pb = ProgressBar::new_spinner();
+
+    let style = ProgressStyle::default_spinner()
+        .template("[{elapsed_precise}] {spinner} {pos} done {msg}")
+        .expect("Invalid progress bar template");
+
+    pb.set_style(style);
+    pb.enable_steady_tick(Duration::from_millis(100));
+    pb
+}
+
+pub(crate) fn create_progress_bar(len: usize) -> ProgressBar {
+    let pb = ProgressBar::new(len as u64);
+    
+    let style = ProgressStyle::default_bar()
+        .template("[{elapsed_precise}] {bar:50} {pos:>3}/{len:3} {msg}")
+        .expect("Invalid progress bar template")
+        .progress_chars("█▉▊▋▌▍▎▏ ");
+    
+    pb.set_style(style);
+    pb
+}
+
+pub(crate) fn print_results(
+    stats: &CompressionStats,
+    columns: &[ResultColumn],
+    processing_time: std::time::Duration,
+    _total_time: std::time::Duration,
+) {
+    if !stats.file_results.is_empty() {
+        let mut table = Table::new();
+        let mut header = vec!["Filename", "Original", "Compressed", "Savings"];
+        for column in columns {
+            header.push(match column {
+                ResultColumn::Ratio => "Ratio",
+                ResultColumn::Time => "Time",
+            });
+        }
+        table.set_header(header);
+
+        for result in &stats.file_results {
+            let original = format_size(result.original_size, DECIMAL);
+            let compressed = format_size(result.compressed_size, DECIMAL);
+            let savings_bytes = result.original_size.saturating_sub(result.compressed_size);
+            let savings = if result.original_size > 0 {
+                format!("{} ({:.1}%)", format_size(savings_bytes, DECIMAL), (savings_bytes as f64 / result.original_size as f64) * 100.0)
+            } else {
+                "0 B (0.0%)".to_string()
+            };
+            let name = result.relative_path.as_ref()
+                .map_or_else(|| result.filename.clone(), |path| path.display().to_string());
+            let mut row = vec![name, original, compressed, savings];
+            for column in columns {
+                row.push(match column {
+                    ResultColumn::Ratio if result.compressed_size > 0 => {
+                        format!("{:.2}x", result.original_size as f64 / result.compressed_size as f64)
+                    }
+                    ResultColumn::Ratio => "-".to_string(),
+                    ResultColumn::Time => format!("{:.2?}", result.duration),
+                });
+            }
+            table.add_row(row);
+        }
+
+        println!("{}", table);
+    }
+
+    let original_text = format_size(stats.original_size, DECIMAL);
+    let compressed_text = format_size(stats.compressed_size, DECIMAL);
+    let savings = stats.savings_percent();
+    let savings_bytes = stats.original_size.saturating_sub(stats.compressed_size);
+    let savings_text = format_size(savings_bytes, DECIMAL);
+    
+    println!();
+    println!("{} files processed", stats.files_processed.to_string().bright_white().bold());
+    if stats.files_skipped > 0 {
+        println!("{} files skipped", stats.files_skipped.to_string().bright_yellow());
+    }
+    if stats.over_budget > 0 {
+        println!("{} files not started (--total-budget reached)", stats.over_budget.to_string().bright_yellow());
+    }
+    if stats.duplicates > 0 {
+        println!("{} duplicates found", stats.duplicates.to_string().bright_yellow());
+    }
+    if stats.grown > 0 {
+        println!("{} files grew", stats.grown.to_string().bright_yellow());
+    }
+    println!("Original: {} → Compressed: {}", original_text.bright_cyan(), compressed_text.bright_cyan());
+    
+    if savings > 0.0 {
+        println!("Saved {} ({:.1}%)", savings_text.bright_green(), savings);
+    }
+    
+    println!("Time: {}", format!("{:.2?}", processing_time).bright_cyan());
+    
+    if !stats.errors.is_empty() {
+        let breakdown = stats.error_counts().iter()
+            .map(|(category, count)| format!("{} {}", count, category.label()))
+            .collect::<Vec<_>>()
+            .join(", ");
+        println!("{} errors ({})", stats.errors.len(), breakdown);
+    }
+}
+
+pub(crate) fn print_dir_breakdown(stats: &CompressionStats, roots: &[PathBuf]) {
+    let groups = stats.group_by_dir(roots);
+    if groups.is_empty() {
+        return;
+    }
+
+    let mut table = Table::new();
+    table.set_header(vec!["Directory", "Files", "Original", "Compressed", "Savings"]);
+
+    for (dir, summary) in &groups {
+        let savings_bytes = summary.original_size.saturating_sub(summary.compressed_size);
+        let savings = if summary.original_size > 0 {
+            format!("{} ({:.1}%)", format_size(savings_bytes, DECIMAL), (savings_bytes as f64 / summary.original_size as f64) * 100.0)
+        } else {
+            "0 B (0.0%)".to_string()
+        };
+        table.add_row(vec![
+            dir.clone(),
+            summary.files.to_string(),
+            format_size(summary.original_size, DECIMAL),
+            format_size(summary.compressed_size, DECIMAL),
+            savings,
+        ]);
+    }
+
+    println!();
+    println!("{}", table);
+}
+
+pub(crate) fn print_similar(stats: &CompressionStats, threshold: u32) {
+    let clusters = similar_clusters(stats, threshold);
+    println!();
+    if clusters.is_empty() {
+        println!("No similar images found (threshold {} bits)", threshold);
+        return;
+    }
+
+    println!("{} groups of similar images (threshold {} bits):", clusters.len(), threshold);
+    for (index, members) in clusters.iter().enumerate() {
+        println!("{}", format!("Group {}", index + 1).bright_cyan());
+        let (_, first_hash) = members[0];
+        for (path, hash) in members {
+            println!("  {} ({} bits from first)", path.display(), (hash ^ first_hash).count_ones());
+        }
+    }
+}
+
+/// Sources tagged with a non-default EXIF orientation, which look wrong once
+/// the tag is gone unless `--auto-orient` rotated the pixels.
+pub(crate) fn print_orientation_summary(stats: &CompressionStats, args: &Args) {
+    // One entry per source; --widths records a result per variant
+    let mut oriented: Vec<(&Path, Orientation)> = Vec::new();
+    for result in &stats.file_results {
+        if let Some(orientation) = result.orientation
+            && !oriented.iter().any(|(path, _)| *path == result.source_path)
+        {
+            oriented.push((&result.source_path, orientation));
+        }
+    }
+    if oriented.is_empty() {
+        return;
+    }
+
+    let note = if args.auto_orient { "applied by --auto-orient" } else { "not applied; see --auto-orient" };
+    println!("{} files had an EXIF orientation ({})", oriented.len().to_string().bright_yellow(), note);
+    if args.verbose {
+        for (path, orientation) in oriented {
+            println!("  {} (EXIF orientation {})", path.display(), orientation.to_exif());
+        }
+    }
+}
+
+pub(crate) fn print_errors(stats: &CompressionStats) {
+    if stats.errors.is_empty() {
+        return;
+    }
+
+    println!();
+    for error in &stats.errors {
+        println!(
+            "{} {}: {}",
+            format!("[{}]", error.category.label()).bright_red(),
+            error.path.display(),
+            error.message
+        );
+    }
+}
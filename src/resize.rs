@@ -0,0 +1,93 @@
+//! Fitting images to `--max-width`/`--max-height` and `--max-pixels`.
+
+use std::borrow::Cow;
+
+use crate::cli::{Args, ResizeMode};
+
+/// Borrows `img` back unchanged when no bound applies, so the common case
+/// costs no pixel copy.
+pub(crate) fn resize_image<'a>(img: &'a image::DynamicImage, args: &Args) -> Cow<'a, image::DynamicImage> {
+    let boxed = resize_to_box(img, args);
+    match resize_to_max_pixels(boxed.as_ref().unwrap_or(img), args) {
+        Some(resized) => Cow::Owned(resized),
+        None => boxed.map_or(Cow::Borrowed(img), Cow::Owned),
+    }
+}
+
+/// `None` when the image already fits.
+pub(crate) fn resize_to_box(img: &image::DynamicImage, args: &Args) -> Option<image::DynamicImage> {
+    use image::imageops::FilterType;
+
+    let (width, height) = (img.width(), img.height());
+    let (box_width, box_height) = match (args.max_width, args.max_height) {
+        (None, None) => return None,
+        (Some(w), Some(h)) => (w, h),
+        // A single bound can only be honoured by fitting inside it
+        (Some(w), None) => (w, u32::MAX),
+        (None, Some(h)) => (u32::MAX, h),
+    };
+    let both_bounds = args.max_width.is_some() && args.max_height.is_some();
+    let mode = if both_bounds { args.resize_mode } else { ResizeMode::Contain };
+
+    let scale_x = f64::from(box_width) / f64::from(width);
+    let scale_y = f64::from(box_height) / f64::from(height);
+
+    match mode {
+        ResizeMode::Contain => {
+            let scale = scale_x.min(scale_y);
+            if scale >= 1.0 {
+                return None;
+            }
+            let new_width = ((f64::from(width) * scale).round() as u32).max(1);
+            let new_height = ((f64::from(height) * scale).round() as u32).max(1);
+            Some(img.resize_exact(new_width, new_height, FilterType::Lanczos3))
+        }
+        ResizeMode::Cover => {
+            // Never upscale; crop whatever overflows the box
+            let scale = scale_x.max(scale_y).min(1.0);
+            let scaled = if scale < 1.0 {
+                let new_width = ((f64::from(width) * scale).round() as u32).max(1);
+                let new_height = ((f64::from(height) * scale).round() as u32).max(1);
+                Cow::Owned(img.resize_exact(new_width, new_height, FilterType::Lanczos3))
+            } else {
+                Cow::Borrowed(img)
+            };
+            let crop_width = box_width.min(scaled.width());
+            let crop_height = box_height.min(scaled.height());
+            if (crop_width, crop_height) == (scaled.width(), scaled.height()) {
+                return match scaled {
+                    Cow::Owned(scaled) => Some(scaled),
+                    Cow::Borrowed(_) => None,
+                };
+            }
+            let x = (scaled.width() - crop_width) / 2;
+            let y = (scaled.height() - crop_height) / 2;
+            Some(scaled.crop_imm(x, y, crop_width, crop_height))
+        }
+        ResizeMode::Fill => {
+            if (width, height) == (box_width, box_height) {
+                return None;
+            }
+            Some(img.resize_exact(box_width, box_height, FilterType::Lanczos3))
+        }
+    }
+}
+
+/// `None` when the image is within `--max-pixels`.
+pub(crate) fn resize_to_max_pixels(img: &image::DynamicImage, args: &Args) -> Option<image::DynamicImage> {
+    use image::imageops::FilterType;
+
+    let max_pixels = args.max_pixels? * 1_000_000.0;
+
+    let (width, height) = (img.width(), img.height());
+    let pixels = f64::from(width) * f64::from(height);
+    if pixels <= max_pixels {
+        return None;
+    }
+
+    // Same scale on both axes keeps the aspect ratio
+    let scale = (max_pixels / pixels).sqrt();
+    let new_width = ((f64::from(width) * scale).floor() as u32).max(1);
+    let new_height = ((f64::from(height) * scale).floor() as u32).max(1);
+    Some(img.resize_exact(new_width, new_height, FilterType::Lanczos3))
+}
@@ -0,0 +1,80 @@
+//! Perceptual hashes and complexity scores used by the summary.
+
+use std::{collections::BTreeMap, path::Path};
+
+use crate::report::CompressionStats;
+
+/// How busy an image is, from 0 (flat) to 1, by the mean luma step across a 256px thumbnail.
+pub(crate) fn image_complexity(img: &image::DynamicImage) -> f64 {
+    // Steps this large on average already read as fully detailed
+    const SATURATION: f64 = 24.0;
+
+    let thumb = img.thumbnail(256, 256).to_luma8();
+    let (width, height) = thumb.dimensions();
+    let (mut total, mut steps) = (0u64, 0u64);
+    for y in 0..height {
+        for x in 0..width {
+            let here = i32::from(thumb.get_pixel(x, y)[0]);
+            if x + 1 < width {
+                total += here.abs_diff(i32::from(thumb.get_pixel(x + 1, y)[0])) as u64;
+                steps += 1;
+            }
+            if y + 1 < height {
+                total += here.abs_diff(i32::from(thumb.get_pixel(x, y + 1)[0])) as u64;
+                steps += 1;
+            }
+        }
+    }
+    if steps == 0 {
+        return 0.0;
+    }
+    (total as f64 / steps as f64 / SATURATION).min(1.0)
+}
+
+/// Difference hash: each of the 64 bits says whether a pixel of the 9x8
+/// grayscale thumbnail is brighter than its right-hand neighbour.
+pub(crate) fn dhash(img: &image::DynamicImage) -> u64 {
+    use image::imageops::FilterType;
+
+    let thumb = img.resize_exact(9, 8, FilterType::Triangle).to_luma8();
+    let mut hash = 0u64;
+    for y in 0..8 {
+        for x in 0..8 {
+            hash <<= 1;
+            if thumb.get_pixel(x, y)[0] > thumb.get_pixel(x + 1, y)[0] {
+                hash |= 1;
+            }
+        }
+    }
+    hash
+}
+
+/// Groups sources whose hashes are within `threshold` bits of each other,
+/// chaining transitively, and returns the groups with more than one member.
+pub(crate) fn similar_clusters(stats: &CompressionStats, threshold: u32) -> Vec<Vec<(&Path, u64)>> {
+    // One entry per source; --widths records a result per variant
+    let mut sources: Vec<(&Path, u64)> = Vec::new();
+    for result in &stats.file_results {
+        if let Some(hash) = result.dhash
+            && !sources.iter().any(|(path, _)| *path == result.source_path)
+        {
+            sources.push((&result.source_path, hash));
+        }
+    }
+
+    let mut cluster_of: Vec<usize> = (0..sources.len()).collect();
+    for i in 0..sources.len() {
+        for j in (i + 1)..sources.len() {
+            if (sources[i].1 ^ sources[j].1).count_ones() <= threshold {
+                let (from, to) = (cluster_of[j], cluster_of[i]);
+                cluster_of.iter_mut().filter(|c| **c == from).for_each(|c| *c = to);
+            }
+        }
+    }
+
+    let mut clusters: BTreeMap<usize, Vec<(&Path, u64)>> = BTreeMap::new();
+    for (source, cluster) in sources.into_iter().zip(cluster_of) {
+        clusters.entry(cluster).or_default().push(source);
+    }
+    clusters.into_values().filter(|members| members.len() > 1).collect()
+}
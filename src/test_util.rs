@@ -0,0 +1,18 @@
+//! Fixtures shared by the unit tests.
+
+use clap::FromArgMatches;
+
+use crate::cli::EncoderArgs;
+
+pub(crate) fn encoder_args(flags: &[&str]) -> EncoderArgs {
+    let command = <EncoderArgs as clap::Args>::augment_args(clap::Command::new("test"));
+    let matches = command.get_matches_from(std::iter::once("test").chain(flags.iter().copied()));
+    EncoderArgs::from_arg_matches(&matches).unwrap()
+}
+
+pub(crate) fn noisy_rgb(width: u32, height: u32) -> image::DynamicImage {
+    image::DynamicImage::ImageRgb8(image::RgbImage::from_fn(width, height, |x, y| {
+        let n = x.wrapping_mul(2_654_435_761).wrapping_add(y.wrapping_mul(40_503)) >> 7;
+        image::Rgb([(n % 256) as u8, ((x * 3 + y) % 256) as u8, ((x ^ y) % 256) as u8])
+    }))
+}
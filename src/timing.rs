@@ -0,0 +1,89 @@
+//! Per-stage wall-clock totals for `--timings`.
+
+use colored::Colorize;
+use comfy_table::Table;
+use std::{
+    sync::{atomic::{AtomicU64, Ordering}, OnceLock},
+    time::{Duration, Instant},
+};
+
+/// `--profile` stage totals, set once before any work starts.
+pub(crate) static STAGE_TIMES: OnceLock<StageTimes> = OnceLock::new();
+
+#[derive(Clone, Copy)]
+pub(crate) enum Stage {
+    Decode,
+    Resize,
+    Encode,
+    Write,
+}
+
+impl Stage {
+    pub(crate) const ALL: [Self; 4] = [Self::Decode, Self::Resize, Self::Encode, Self::Write];
+
+    pub(crate) const fn label(self) -> &'static str {
+        match self {
+            Self::Decode => "decode",
+            Self::Resize => "resize",
+            Self::Encode => "encode",
+            Self::Write => "write",
+        }
+    }
+}
+
+/// Nanoseconds per stage, one row per worker so threads don't contend on a
+/// shared counter; the last row takes threads outside the pool (`--timeout`).
+pub(crate) struct StageTimes {
+    pub(crate) rows: Vec<[AtomicU64; 4]>,
+}
+
+impl StageTimes {
+    pub(crate) fn new(threads: usize) -> Self {
+        Self { rows: (0..=threads).map(|_| Default::default()).collect() }
+    }
+
+    pub(crate) fn record(&self, stage: Stage, elapsed: Duration) {
+        let row = rayon::current_thread_index()
+            .and_then(|index| self.rows.get(index))
+            .unwrap_or(&self.rows[self.rows.len() - 1]);
+        row[stage as usize].fetch_add(u64::try_from(elapsed.as_nanos()).unwrap_or(u64::MAX), Ordering::Relaxed);
+    }
+
+    pub(crate) fn total(&self, stage: Stage) -> Duration {
+        Duration::from_nanos(self.rows.iter().map(|row| row[stage as usize].load(Ordering::Relaxed)).sum())
+    }
+}
+
+/// Runs `op`, adding its time to `stage` under `--profile`.
+pub(crate) fn timed<T>(stage: Stage, op: impl FnOnce() -> T) -> T {
+    let Some(times) = STAGE_TIMES.get() else {
+        return op();
+    };
+    let started = Instant::now();
+    let result = op();
+    times.record(stage, started.elapsed());
+    result
+}
+
+/// `--profile`: stage totals add up time on every worker, so next to the wall
+/// clock they show how well the stages kept the pool busy.
+pub(crate) fn print_stage_times(times: &StageTimes, processing_time: Duration) {
+    let total: Duration = Stage::ALL.iter().map(|&stage| times.total(stage)).sum();
+
+    let mut table = Table::new();
+    table.set_header(vec!["Stage", "Time", "Share"]);
+    for stage in Stage::ALL {
+        let time = times.total(stage);
+        let share = if total.is_zero() { 0.0 } else { time.as_secs_f64() / total.as_secs_f64() * 100.0 };
+        table.add_row(vec![stage.label().to_string(), format!("{:.2?}", time), format!("{:.1}%", share)]);
+    }
+
+    println!();
+    println!("{}", table);
+    println!(
+        "Stages: {} across {} threads in {} wall time",
+        format!("{:.2?}", total).bright_cyan(),
+        num_cpus::get().max(1),
+        format!("{:.2?}", processing_time).bright_cyan()
+    );
+}